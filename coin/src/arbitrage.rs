@@ -0,0 +1,340 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use chrono;
+
+use clock::Clock;
+use consolidated::ConsolidatedBook;
+use error::Error;
+use event::{EventSink, Feed, FeedEvent};
+use price::Price;
+use quantity::Quantity;
+use side::Side;
+use time::Time;
+
+/// A period during which buying on one venue and selling on another was
+/// profitable after taker fees.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Opportunity {
+    /// The venue the instrument is bought on, at its best ask.
+    pub buy_venue: Feed,
+    /// The venue the instrument is sold on, at its best bid.
+    pub sell_venue: Feed,
+    /// When the venues first crossed.
+    pub start: Time,
+    /// When the crossing was last seen.
+    pub end: Time,
+    /// The best expected profit seen during the opportunity, in quote currency,
+    /// together with the size and the top of book prices it was seen at.
+    pub expected_profit: f64,
+    /// The size that could be bought and sold at a profit.
+    pub size: Quantity,
+    /// The best ask of the buy venue.
+    pub buy_price: Price,
+    /// The best bid of the sell venue.
+    pub sell_price: Price,
+}
+
+impl Opportunity {
+    /// How long the opportunity lasted.
+    pub fn duration(&self) -> chrono::Duration {
+        self.end.signed_duration_since(&self.start)
+    }
+}
+
+/// Receives the opportunities found by an ArbitrageDetector.
+pub trait OpportunitySink {
+    /// Called once the opportunity has disappeared.
+    fn on_opportunity(&self, opportunity: &Opportunity);
+}
+
+impl<F: Fn(&Opportunity)> OpportunitySink for F {
+    fn on_opportunity(&self, opportunity: &Opportunity) {
+        self(opportunity)
+    }
+}
+
+// The size and expected profit of taking the asks of one book and hitting the
+// bids of another, as long as each unit is profitable after the taker fees.
+fn crossing(asks: &[(Price, Quantity)], buy_fee: f64, bids: &[(Price, Quantity)], sell_fee: f64) -> Result<(Quantity, f64), Error> {
+    let overflow = || Error::Protocol("crossing size overflow".to_string());
+    let mut size = Quantity::zero();
+    let mut profit = 0.0;
+    let (mut asks, mut bids) = (asks.iter().cloned(), bids.iter().cloned());
+    let (mut ask, mut bid) = (asks.next(), bids.next());
+    while let (Some((ask_price, ask_size)), Some((bid_price, bid_size))) = (ask, bid) {
+        let unit_profit = bid_price.to_float() * (1.0 - sell_fee) - ask_price.to_float() * (1.0 + buy_fee);
+        if unit_profit <= 0.0 {
+            break
+        }
+        let filled = std::cmp::min(ask_size, bid_size);
+        size.accumulate(filled)?;
+        profit += unit_profit * filled.to_float();
+        ask = if filled == ask_size { asks.next() } else { Some((ask_price, ask_size.checked_sub(filled).ok_or_else(overflow)?)) };
+        bid = if filled == bid_size { bids.next() } else { Some((bid_price, bid_size.checked_sub(filled).ok_or_else(overflow)?)) };
+    }
+    Ok((size, profit))
+}
+
+/// Detects when the best bid of a venue exceeds the best ask of another one by
+/// more than the taker fees of both venues. The opportunities are reported to
+/// the sinks once they have disappeared.
+pub struct ArbitrageDetector {
+    book: ConsolidatedBook,
+    // Taker fees as a fraction of the traded notional, e.g. 0.0025.
+    taker_fees: BTreeMap<Feed, f64>,
+    // The number of levels of each book that are considered.
+    depth: usize,
+    open: RefCell<BTreeMap<(Feed, Feed), Opportunity>>,
+    sinks: RefCell<Vec<Box<dyn OpportunitySink>>>,
+    // The venues are checked at the time of this clock when set, otherwise at
+    // the receive time of the events.
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl ArbitrageDetector {
+    /// Takes the product id of the instrument on each venue, the fees default to zero.
+    pub fn new(products: &[(Feed, &str)]) -> ArbitrageDetector {
+        ArbitrageDetector {
+            book: ConsolidatedBook::new(products),
+            taker_fees: BTreeMap::new(),
+            depth: 10,
+            open: RefCell::new(BTreeMap::new()),
+            sinks: RefCell::new(Vec::new()),
+            clock: None,
+        }
+    }
+
+    /// Evaluates the staleness of the venues at the time of the clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> ArbitrageDetector {
+        ArbitrageDetector { clock: Some(clock), ..self }
+    }
+
+    /// Sets the taker fee of a venue, as a fraction of the traded notional.
+    pub fn with_taker_fee(mut self, feed: Feed, fee: f64) -> ArbitrageDetector {
+        self.taker_fees.insert(feed, fee);
+        self
+    }
+
+    /// Sets the number of levels of each book that are considered, 10 by default.
+    pub fn with_depth(self, depth: usize) -> ArbitrageDetector {
+        ArbitrageDetector { depth, ..self }
+    }
+
+    /// Adds a sink that receives the closed opportunities.
+    pub fn subscribe(&self, sink: Box<dyn OpportunitySink>) {
+        self.sinks.borrow_mut().push(sink);
+    }
+
+    /// The consolidated book the venues are compared on.
+    pub fn book(&self) -> &ConsolidatedBook {
+        &self.book
+    }
+
+    fn taker_fee(&self, feed: Feed) -> f64 {
+        self.taker_fees.get(&feed).cloned().unwrap_or(0.0)
+    }
+
+    /// The opportunities that are currently open, by buy and sell venue.
+    pub fn open_opportunities(&self) -> Vec<Opportunity> {
+        self.open.borrow().values().cloned().collect()
+    }
+
+    /// Re-evaluates all the venue pairs, closing the opportunities that are gone.
+    pub fn check(&self, time: &Time) {
+        let venues = self.book.live_venues(time);
+        let mut closed = Vec::new();
+        {
+            let mut open = self.open.borrow_mut();
+            for &buy_venue in venues.iter() {
+                for &sell_venue in venues.iter().filter(|&&venue| venue != buy_venue) {
+                    let asks = self.book.venue_book(buy_venue).unwrap().top_levels(Side::Sell, self.depth);
+                    let bids = self.book.venue_book(sell_venue).unwrap().top_levels(Side::Buy, self.depth);
+                    let (size, profit) = match crossing(&asks, self.taker_fee(buy_venue), &bids, self.taker_fee(sell_venue)) {
+                        Ok(crossing) => crossing,
+                        Err(error) => {
+                            error!("skipping {} -> {}: {}", buy_venue, sell_venue, error);
+                            continue
+                        },
+                    };
+                    let key = (buy_venue, sell_venue);
+                    if size.is_zero() {
+                        closed.extend(open.remove(&key));
+                        continue
+                    }
+                    let opportunity = open.entry(key).or_insert_with(|| {
+                        info!("opportunity buying on {} at {} and selling on {} at {}", buy_venue, asks[0].0, sell_venue, bids[0].0);
+                        Opportunity {
+                            buy_venue,
+                            sell_venue,
+                            start: time.clone(),
+                            end: time.clone(),
+                            expected_profit: 0.0,
+                            size,
+                            buy_price: asks[0].0,
+                            sell_price: bids[0].0,
+                        }
+                    });
+                    opportunity.end = time.clone();
+                    if profit > opportunity.expected_profit {
+                        opportunity.expected_profit = profit;
+                        opportunity.size = size;
+                        opportunity.buy_price = asks[0].0;
+                        opportunity.sell_price = bids[0].0;
+                    }
+                }
+            }
+            // The pairs involving a venue that dropped out are closed too.
+            let gone: Vec<_> = open.keys()
+                .filter(|(buy_venue, sell_venue)| !venues.contains(buy_venue) || !venues.contains(sell_venue))
+                .cloned()
+                .collect();
+            for key in gone {
+                closed.extend(open.remove(&key));
+            }
+        }
+        for mut opportunity in closed {
+            opportunity.end = time.clone();
+            self.publish(&opportunity);
+        }
+    }
+
+    /// Closes all the open opportunities, e.g. at the end of a replay.
+    pub fn flush(&self, time: &Time) {
+        let open: Vec<_> = std::mem::take(&mut *self.open.borrow_mut()).into_values().collect();
+        for mut opportunity in open {
+            opportunity.end = time.clone();
+            self.publish(&opportunity);
+        }
+    }
+
+    fn publish(&self, opportunity: &Opportunity) {
+        info!("opportunity closed after {}ms, expected profit {:.2} for {}: {:?}",
+            opportunity.duration().num_milliseconds(), opportunity.expected_profit, opportunity.size, opportunity);
+        for sink in self.sinks.borrow().iter() {
+            sink.on_opportunity(opportunity);
+        }
+    }
+}
+
+impl EventSink for ArbitrageDetector {
+    fn on_event(&self, event: &FeedEvent) {
+        self.book.on_event(event);
+        // Each snapshot level would trigger a check, the book is checked once the
+        // snapshot is complete.
+        if !self.book.in_snapshot() {
+            match self.clock {
+                Some(ref clock) => self.check(&clock.now()),
+                None => self.check(&event.time),
+            }
+        }
+    }
+
+    fn on_end(&self, time: &Time) {
+        self.flush(time);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+    use event::{Event, EventBus};
+
+    fn price(str: &str) -> Price {
+        Price::parse_str(str).unwrap()
+    }
+
+    fn qty(str: &str) -> Quantity {
+        Quantity::parse_str(str).unwrap()
+    }
+
+    fn level(feed: Feed, time: &str, product_id: &str, side: Side, price_: &str, size: &str) -> FeedEvent {
+        let event = Event::LevelUpdate { product_id: product_id.to_string(), side, price: price(price_), size: qty(size) };
+        FeedEvent { feed, time: Time::parse(time).unwrap(), event }
+    }
+
+    fn live(feed: Feed, time: &str, product_id: &str) -> FeedEvent {
+        let event = Event::StatusChange { product_id: product_id.to_string(), status: Ok(()) };
+        FeedEvent { feed, time: Time::parse(time).unwrap(), event }
+    }
+
+    #[test]
+    fn crossing_test() {
+        let asks = [(price("100"), qty("1")), (price("101"), qty("2"))];
+        let bids = [(price("102"), qty("2")), (price("100"), qty("5"))];
+        assert_eq!(crossing(&asks, 0.0, &bids, 0.0), Ok((qty("2"), 3.0)));
+        let (size, profit) = crossing(&asks, 0.005, &bids, 0.005).unwrap();
+        assert_eq!(size, qty("1"));
+        assert!((profit - 0.99).abs() < 1e-9);
+        assert_eq!(crossing(&asks, 0.01, &bids, 0.01).unwrap().0, qty("0"));
+    }
+
+    #[test]
+    fn detector_test() {
+        let detector = ArbitrageDetector::new(&[(Feed::Gdax, "BTC-USD"), (Feed::Gemini, "BTCUSD")])
+            .with_taker_fee(Feed::Gdax, 0.001)
+            .with_taker_fee(Feed::Gemini, 0.001);
+        let opportunities = Rc::new(RefCell::new(Vec::new()));
+        {
+            let opportunities = opportunities.clone();
+            detector.subscribe(Box::new(move |opportunity: &Opportunity| opportunities.borrow_mut().push(opportunity.clone())));
+        }
+        let t0 = "2018-01-17 21:00:00.000000000";
+        detector.on_event(&level(Feed::Gdax, t0, "BTC-USD", Side::Buy, "9990", "1"));
+        detector.on_event(&level(Feed::Gdax, t0, "BTC-USD", Side::Sell, "10000", "1"));
+        detector.on_event(&level(Feed::Gemini, t0, "BTCUSD", Side::Buy, "10010", "2"));
+        detector.on_event(&live(Feed::Gdax, t0, "BTC-USD"));
+        detector.on_event(&live(Feed::Gemini, t0, "BTCUSD"));
+        // Not enough to pay for the fees.
+        assert!(detector.open_opportunities().is_empty());
+        detector.on_event(&level(Feed::Gemini, t0, "BTCUSD", Side::Sell, "10050", "2"));
+        let t1 = "2018-01-17 21:00:00.100000000";
+        detector.on_event(&level(Feed::Gemini, t1, "BTCUSD", Side::Buy, "10030", "2"));
+        let open = detector.open_opportunities();
+        assert_eq!(open.len(), 1);
+        assert_eq!((open[0].buy_venue, open[0].sell_venue), (Feed::Gdax, Feed::Gemini));
+        assert_eq!(open[0].size, qty("1"));
+        assert!((open[0].expected_profit - 9.97).abs() < 1e-6);
+
+        let t2 = "2018-01-17 21:00:00.350000000";
+        detector.on_event(&level(Feed::Gdax, t2, "BTC-USD", Side::Sell, "10000", "0"));
+        assert!(detector.open_opportunities().is_empty());
+        let opportunities = opportunities.borrow();
+        assert_eq!(opportunities.len(), 1);
+        assert_eq!(opportunities[0].buy_price, price("10000"));
+        assert_eq!(opportunities[0].sell_price, price("10030"));
+        assert_eq!(opportunities[0].duration().num_milliseconds(), 250);
+    }
+
+    #[test]
+    fn end_test() {
+        let detector = ArbitrageDetector::new(&[(Feed::Gdax, "BTC-USD"), (Feed::Gemini, "BTCUSD")]);
+        let opportunities = Rc::new(RefCell::new(Vec::new()));
+        {
+            let opportunities = opportunities.clone();
+            detector.subscribe(Box::new(move |opportunity: &Opportunity| opportunities.borrow_mut().push(opportunity.clone())));
+        }
+        let bus = EventBus::new();
+        bus.subscribe(Box::new(detector));
+        let t0 = "2018-01-17 21:00:00.000000000";
+        let snapshot = |product_id: &str| Event::SnapshotStart { product_id: product_id.to_string() };
+        bus.publish(&FeedEvent { feed: Feed::Gdax, time: Time::parse(t0).unwrap(), event: snapshot("BTC-USD") });
+        bus.publish(&level(Feed::Gdax, t0, "BTC-USD", Side::Sell, "10000", "1"));
+        bus.publish(&FeedEvent { feed: Feed::Gdax, time: Time::parse(t0).unwrap(), event: Event::SnapshotEnd { product_id: "BTC-USD".to_string() } });
+        bus.publish(&level(Feed::Gdax, t0, "BTC-USD", Side::Buy, "9990", "1"));
+        bus.publish(&level(Feed::Gemini, t0, "BTCUSD", Side::Buy, "10030", "2"));
+        bus.publish(&level(Feed::Gemini, t0, "BTCUSD", Side::Sell, "10050", "2"));
+        bus.publish(&live(Feed::Gdax, t0, "BTC-USD"));
+        bus.publish(&live(Feed::Gemini, t0, "BTCUSD"));
+        let t1 = "2018-01-17 21:00:00.100000000";
+        bus.publish(&level(Feed::Gemini, t1, "BTCUSD", Side::Buy, "10020", "1"));
+        assert!(opportunities.borrow().is_empty());
+        // The opportunity still open at the end of the stream is reported.
+        bus.end();
+        let opportunities = opportunities.borrow();
+        assert_eq!(opportunities.len(), 1);
+        assert_eq!(opportunities[0].duration().num_milliseconds(), 100);
+    }
+}
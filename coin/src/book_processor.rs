@@ -1,109 +1,285 @@
+use std;
 use std::collections::BTreeMap;
+use std::time::Duration;
 
+use error::Error;
 use side::Side;
 use price::Price;
+use quantity::Quantity;
 use time::Time;
 
+/// How long a book can go without updates before it is stale, unless configured otherwise.
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_millis(500);
+
 // The current snapshot status, starts with InitialSnapshot and moves to PostSnapshot
 // once a non-snapshot update has been received.
 enum SnapshotStatus {
     InitialSnapshot,
     PostSnapshot,
     Error, // Received a snapshot update after a non-snapshot one, this cannot be recovered from.
+    SequenceGap, // Some updates have been missed, only a new snapshot can recover from this.
 }
 
 impl SnapshotStatus {
     fn update(&self, initial_snapshot: bool) -> SnapshotStatus {
-        match self {
-            &SnapshotStatus::InitialSnapshot => {
+        match *self {
+            SnapshotStatus::InitialSnapshot => {
                 if initial_snapshot {
                     SnapshotStatus::InitialSnapshot
                 } else {
                     SnapshotStatus::PostSnapshot
                 }
             },
-            &SnapshotStatus::PostSnapshot => {
+            SnapshotStatus::PostSnapshot => {
                 if initial_snapshot {
                     SnapshotStatus::Error
                 } else {
                     SnapshotStatus::PostSnapshot
                 }
             },
-            &SnapshotStatus::Error => SnapshotStatus::Error,
+            SnapshotStatus::Error => SnapshotStatus::Error,
+            SnapshotStatus::SequenceGap => SnapshotStatus::SequenceGap,
         }
     }
 }
 
-// The different reasons for which the book data should not be used.
+/// The different reasons for which the book data should not be used.
+#[derive(Clone, Debug, PartialEq)]
 pub enum NotLiveStatus {
+    /// The first snapshot has not been received yet.
     InitialSnapshot,
+    /// The snapshot could not be applied.
     SnapshotError,
+    /// Messages have been missed since the last snapshot.
+    SequenceGap,
+    /// The book has not been updated for too long.
     Stale,
 }
 
+/// The order book of a single product, as price levels with their total size.
 pub struct BookProcessor {
-    bid_sizes: BTreeMap< Price, f64 >,
-    ask_sizes: BTreeMap< Price, f64 >,
-    total_bid_size: f64,
-    total_ask_size: f64,
+    bid_sizes: BTreeMap< Price, Quantity >,
+    ask_sizes: BTreeMap< Price, Quantity >,
+    total_bid_size: Quantity,
+    total_ask_size: Quantity,
     last_update: Time,
     snapshot_status: SnapshotStatus,
+    // The book is stale when it has not been updated for longer than this.
+    stale_after: Duration,
 }
 
 // TODO: take the product name as argument + assert that it is correct.
 // TODO: on_error (call clear_on_snapshot).
+impl Default for BookProcessor {
+    fn default() -> BookProcessor {
+        BookProcessor::new()
+    }
+}
+
 impl BookProcessor {
+    /// An empty book, waiting for its initial snapshot.
     pub fn new() -> BookProcessor {
         BookProcessor {
             bid_sizes: BTreeMap::new(),
             ask_sizes: BTreeMap::new(),
-            total_bid_size: 0.0,
-            total_ask_size: 0.0,
+            total_bid_size: Quantity::zero(),
+            total_ask_size: Quantity::zero(),
             last_update: Time::epoch(),
             snapshot_status: SnapshotStatus::InitialSnapshot,
+            stale_after: DEFAULT_STALE_AFTER,
         }
     }
 
+    /// How long the book can go without updates before it is stale.
+    pub fn set_stale_after(&mut self, stale_after: Duration) {
+        self.stale_after = stale_after;
+    }
+
+    /// Empties the book before a new snapshot is applied.
     pub fn clear_on_snapshot(&mut self) {
         self.bid_sizes.clear();
         self.ask_sizes.clear();
-        self.total_bid_size = 0.0;
-        self.total_ask_size = 0.0;
+        self.total_bid_size = Quantity::zero();
+        self.total_ask_size = Quantity::zero();
         self.last_update = Time::epoch();
         self.snapshot_status = SnapshotStatus::InitialSnapshot;
     }
 
+    /// Marks the book as not live until the next snapshot.
+    pub fn on_sequence_gap(&mut self) {
+        self.snapshot_status = SnapshotStatus::SequenceGap;
+    }
+
+    /// Logs the number of levels and the top of the book.
     pub fn log_summary(&self) {
-        let best_bid = self.bid_sizes.iter().next_back();
-        let best_ask = self.ask_sizes.iter().next();
         info!("bid/ask levels {}/{}: {:?} {:?}",
             self.bid_sizes.len(),
             self.ask_sizes.len(),
-            best_bid,
-            best_ask);
+            self.best_bid(),
+            self.best_ask());
     }
 
-    pub fn on_update(&mut self, time: &Time, side: Side, price: Price, size: f64, initial_snapshot: bool) {
-        self.last_update = time.clone();
-        self.snapshot_status = self.snapshot_status.update(initial_snapshot);
-        let ref mut to_update = match side {
-            Side::Buy => &mut self.bid_sizes,
-            Side::Sell => &mut self.ask_sizes,
+    /// The highest bid with its size.
+    pub fn best_bid(&self) -> Option<(Price, Quantity)> {
+        self.bid_sizes.iter().next_back().map(|(&price, &size)| (price, size))
+    }
+
+    /// The lowest ask with its size.
+    pub fn best_ask(&self) -> Option<(Price, Quantity)> {
+        self.ask_sizes.iter().next().map(|(&price, &size)| (price, size))
+    }
+
+    /// The midpoint between the best bid and the best ask.
+    pub fn mid(&self) -> Option<Price> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => Some(bid.midpoint(&ask)),
+            _ => None,
+        }
+    }
+
+    /// The best ask minus the best bid, this is negative if the book is crossed.
+    pub fn spread(&self) -> Option<Price> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => Some(ask - bid),
+            _ => None,
+        }
+    }
+
+    /// The total size over all the bid levels.
+    pub fn total_bid_size(&self) -> Quantity {
+        self.total_bid_size
+    }
+
+    /// The total size over all the ask levels.
+    pub fn total_ask_size(&self) -> Quantity {
+        self.total_ask_size
+    }
+
+    // (bid - ask) / (bid + ask) in [-1, 1], positive when there is more size on the bid.
+    fn imbalance(bid_size: Quantity, ask_size: Quantity) -> Option<f64> {
+        let (bid_size, ask_size) = (bid_size.to_float(), ask_size.to_float());
+        let total = bid_size + ask_size;
+        if total > 0.0 {
+            Some((bid_size - ask_size) / total)
+        } else {
+            None
+        }
+    }
+
+    /// The imbalance between the sizes at the best bid and at the best ask.
+    pub fn top_imbalance(&self) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((_, bid_size)), Some((_, ask_size))) => BookProcessor::imbalance(bid_size, ask_size),
+            _ => None,
+        }
+    }
+
+    /// The imbalance between the sizes of the levels that are at most `ticks`
+    /// ticks away from the best price on their side.
+    pub fn depth_imbalance(&self, ticks: i64, tick_size: &Price) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => {
+                let bid_size = self.depth_at(Side::Buy, bid.offset(-ticks, tick_size)).ok()?;
+                let ask_size = self.depth_at(Side::Sell, ask.offset(ticks, tick_size)).ok()?;
+                BookProcessor::imbalance(bid_size, ask_size)
+            },
+            _ => None,
+        }
+    }
+
+    /// The imbalance between the total sizes of both sides of the book.
+    pub fn book_imbalance(&self) -> Option<f64> {
+        BookProcessor::imbalance(self.total_bid_size, self.total_ask_size)
+    }
+
+    /// The mid price weighted by the sizes at the top of the book, i.e. it is
+    /// closer to the ask when there is more size on the bid.
+    pub fn microprice(&self) -> Option<Price> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, bid_size)), Some((ask, ask_size))) => {
+                Price::weighted_average(vec![(bid, ask_size), (ask, bid_size)])
+            },
+            _ => None,
+        }
+    }
+
+    // Iterates over the levels of one side of the book, best price first. Side::Buy
+    // designates the bids.
+    fn levels<'a>(&'a self, side: Side) -> Box<dyn Iterator<Item=(&'a Price, &'a Quantity)> + 'a> {
+        match side {
+            Side::Buy => Box::new(self.bid_sizes.iter().rev()),
+            Side::Sell => Box::new(self.ask_sizes.iter()),
+        }
+    }
+
+    /// The n best levels of one side of the book, best price first.
+    pub fn top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
+        self.levels(side)
+            .take(n)
+            .map(|(&price, &size)| (price, size))
+            .collect()
+    }
+
+    /// The cumulative size of the levels on one side of the book with a price at
+    /// least as good as `price`, i.e. above it for bids and below it for asks.
+    pub fn depth_at(&self, side: Side, price: Price) -> Result<Quantity, Error> {
+        let depth = match side {
+            Side::Buy => Quantity::checked_sum(self.bid_sizes.range(price..).map(|(_, &size)| size)),
+            Side::Sell => Quantity::checked_sum(self.ask_sizes.range(..=price).map(|(_, &size)| size)),
         };
-        if size == 0.0 {
+        depth.ok_or_else(|| Error::Protocol(format!("{:?} depth overflow at {}", side, price)))
+    }
+
+    /// The volume weighted average price at which an order on `side` for `size`
+    /// would be filled, e.g. a buy order is filled against the asks. Returns None
+    /// if the book is not deep enough.
+    pub fn fill_price(&self, side: Side, size: Quantity) -> Result<Option<Price>, Error> {
+        if size <= Quantity::zero() {
+            return Ok(None)
+        }
+        let mut remaining = size;
+        let mut fills = Vec::new();
+        for (&price, &level_size) in self.levels(side.opposite()) {
+            let filled = std::cmp::min(remaining, level_size);
+            fills.push((price, filled));
+            remaining = remaining.checked_sub(filled)
+                .ok_or_else(|| Error::Protocol(format!("fill size overflow for {}", size)))?;
+            if remaining.is_zero() {
+                return Ok(Price::weighted_average(fills))
+            }
+        }
+        Ok(None)
+    }
+
+    /// The book is left untouched if the total size of the side would overflow.
+    pub fn on_update(&mut self, time: &Time, side: Side, price: Price, size: Quantity, initial_snapshot: bool) -> Result<(), Error> {
+        let (to_update, total) = match side {
+            Side::Buy => (&mut self.bid_sizes, &mut self.total_bid_size),
+            Side::Sell => (&mut self.ask_sizes, &mut self.total_ask_size),
+        };
+        let previous_size = to_update.get(&price).cloned().unwrap_or_default();
+        *total = total.checked_add(size)
+            .and_then(|total| total.checked_sub(previous_size))
+            .ok_or_else(|| Error::Protocol(format!("{:?} size overflow at {} adding {}", side, price, size)))?;
+        if size.is_zero() {
             to_update.remove(&price);
         } else {
             to_update.insert(price, size);
         }
+        self.last_update = time.clone();
+        self.snapshot_status = self.snapshot_status.update(initial_snapshot);
+        Ok(())
     }
 
+    /// Whether the book can be used at `time`, or the reason why it cannot.
     pub fn status(&self, time: &Time) -> Result<(), NotLiveStatus> {
         match self.snapshot_status {
             SnapshotStatus::InitialSnapshot => Err(NotLiveStatus::InitialSnapshot),
             SnapshotStatus::Error => Err(NotLiveStatus::SnapshotError),
+            SnapshotStatus::SequenceGap => Err(NotLiveStatus::SequenceGap),
             SnapshotStatus::PostSnapshot => {
                 let time_since_last_update = time.signed_duration_since(&self.last_update);
-                if time_since_last_update.num_milliseconds() > 500 {
+                if time_since_last_update.num_milliseconds() > self.stale_after.as_millis() as i64 {
                     Err(NotLiveStatus::Stale)
                 } else {
                     Ok(())
@@ -112,3 +288,101 @@ impl BookProcessor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn price(str: &str) -> Price {
+        Price::parse_str(str).unwrap()
+    }
+
+    fn qty(str: &str) -> Quantity {
+        Quantity::parse_str(str).unwrap()
+    }
+
+    fn book() -> BookProcessor {
+        let mut book = BookProcessor::new();
+        let now = Time::now();
+        book.on_update(&now, Side::Buy, price("99"), qty("1"), true).unwrap();
+        book.on_update(&now, Side::Buy, price("98"), qty("2"), true).unwrap();
+        book.on_update(&now, Side::Buy, price("97"), qty("3"), true).unwrap();
+        book.on_update(&now, Side::Sell, price("101"), qty("1.5"), true).unwrap();
+        book.on_update(&now, Side::Sell, price("102"), qty("2.5"), true).unwrap();
+        book
+    }
+
+    #[test]
+    fn query_test() {
+        let mut book = book();
+        assert_eq!(book.best_bid(), Some((price("99"), qty("1"))));
+        assert_eq!(book.best_ask(), Some((price("101"), qty("1.5"))));
+        assert_eq!(book.mid(), Some(price("100")));
+        assert_eq!(book.spread(), Some(price("2")));
+        assert_eq!(book.top_levels(Side::Buy, 2), vec![(price("99"), qty("1")), (price("98"), qty("2"))]);
+        assert_eq!(book.top_levels(Side::Sell, 5), vec![(price("101"), qty("1.5")), (price("102"), qty("2.5"))]);
+        assert_eq!(book.depth_at(Side::Buy, price("98")), Ok(qty("3")));
+        assert_eq!(book.depth_at(Side::Buy, price("97.5")), Ok(qty("3")));
+        assert_eq!(book.depth_at(Side::Buy, price("100")), Ok(qty("0")));
+        assert_eq!(book.depth_at(Side::Sell, price("102")), Ok(qty("4")));
+        assert_eq!(book.fill_price(Side::Buy, qty("1")), Ok(Some(price("101"))));
+        assert_eq!(book.fill_price(Side::Buy, qty("2")), Ok(Some(price("101.25"))));
+        assert_eq!(book.fill_price(Side::Sell, qty("3")), Ok(Some(price("98.333333333"))));
+        assert_eq!(book.fill_price(Side::Buy, qty("5")), Ok(None));
+
+        book.on_update(&Time::now(), Side::Sell, price("101"), qty("0"), false).unwrap();
+        assert_eq!(book.best_ask(), Some((price("102"), qty("2.5"))));
+        assert_eq!(book.total_ask_size(), qty("2.5"));
+        book.clear_on_snapshot();
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(book.mid(), None);
+        assert_eq!(book.spread(), None);
+    }
+
+    #[test]
+    fn large_size_test() {
+        // The sizes of small-cap pairs go beyond 1e9.
+        let mut book = BookProcessor::new();
+        let now = Time::now();
+        book.on_update(&now, Side::Buy, price("0.00001"), qty("500000000"), true).unwrap();
+        book.on_update(&now, Side::Buy, price("0.00002"), qty("500000000"), true).unwrap();
+        book.on_update(&now, Side::Sell, price("0.00003"), qty("5000000000"), true).unwrap();
+        assert_eq!(book.total_bid_size(), qty("1000000000"));
+        assert_eq!(book.depth_at(Side::Buy, price("0.00001")), Ok(qty("1000000000")));
+        assert_eq!(book.fill_price(Side::Sell, qty("1000000000")), Ok(Some(price("0.000015"))));
+
+        // An update that would overflow the total is refused and leaves the book as is.
+        let mut book = BookProcessor::new();
+        book.on_update(&now, Side::Buy, price("1"), qty("9000000000000000000"), true).unwrap();
+        assert!(book.on_update(&now, Side::Buy, price("2"), qty("9000000000000000000"), true).is_err());
+        assert_eq!(book.best_bid(), Some((price("1"), qty("9000000000000000000"))));
+        assert_eq!(book.total_bid_size(), qty("9000000000000000000"));
+    }
+
+    #[test]
+    fn imbalance_test() {
+        let mut book = book();
+        let now = Time::now();
+        assert_eq!(book.total_bid_size(), qty("6"));
+        assert_eq!(book.total_ask_size(), qty("4"));
+        assert_eq!(book.book_imbalance(), Some(0.2));
+        assert_eq!(book.top_imbalance(), Some(-0.2));
+        assert_eq!(book.depth_imbalance(0, &price("1")), Some(-0.2));
+        assert_eq!(book.depth_imbalance(1, &price("1")), Some(-1.0 / 7.0));
+        assert_eq!(book.depth_imbalance(2, &price("1")), Some(0.2));
+        assert_eq!(book.microprice(), Some(price("99.8")));
+
+        book.on_update(&now, Side::Buy, price("98"), qty("4"), false).unwrap();
+        book.on_update(&now, Side::Buy, price("99"), qty("0"), false).unwrap();
+        book.on_update(&now, Side::Buy, price("99"), qty("0"), false).unwrap();
+        book.on_update(&now, Side::Sell, price("103"), qty("1"), false).unwrap();
+        assert_eq!(book.total_bid_size(), qty("7"));
+        assert_eq!(book.total_ask_size(), qty("5"));
+
+        book.clear_on_snapshot();
+        assert_eq!(book.total_bid_size(), qty("0"));
+        assert_eq!(book.book_imbalance(), None);
+        assert_eq!(book.top_imbalance(), None);
+        assert_eq!(book.microprice(), None);
+    }
+}
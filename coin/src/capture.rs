@@ -0,0 +1,1196 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2;
+use serde_json;
+use zstd;
+
+use error::Error;
+use event::Feed;
+use time::Time;
+use transport::Endpoint;
+
+/// The version of the capture format, bumped on incompatible changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+// Header lines start with a character that no timestamp starts with.
+const HEADER_PREFIX: &str = "#coin ";
+
+/// Describes how a capture was made, it is written at the top of each capture
+/// file so that the file can be replayed without any other information.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// The version of the capture format.
+    pub format: u32,
+    /// The feed the messages come from.
+    pub feed: Feed,
+    /// The products subscribed to.
+    pub products: Vec<String>,
+    /// The url the messages were received from.
+    pub server_name: String,
+    /// The message sent to subscribe, if any.
+    pub subscribe_message: Option<String>,
+    /// The version of coin that wrote the capture.
+    pub version: String,
+    /// The host that wrote the capture.
+    pub host: String,
+    /// The receive time of the first message of the file.
+    pub start: Time,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawHeader {
+    format: u32,
+    feed: String,
+    products: Vec<String>,
+    server_name: String,
+    subscribe_message: Option<String>,
+    version: String,
+    host: String,
+    start: String,
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/etc/hostname").ok()
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+impl Header {
+    /// The start time is set when the header is written.
+    pub fn new(feed: Feed, products: Vec<String>, endpoint: &Endpoint) -> Header {
+        Header {
+            format: FORMAT_VERSION,
+            feed,
+            products,
+            server_name: endpoint.server_name.clone(),
+            subscribe_message: endpoint.subscribe_message.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            host: hostname(),
+            start: Time::epoch(),
+        }
+    }
+
+    /// The header as written at the top of the text captures.
+    pub fn to_json(&self) -> String {
+        let raw = RawHeader {
+            format: self.format,
+            feed: self.feed.name().to_string(),
+            products: self.products.clone(),
+            server_name: self.server_name.clone(),
+            subscribe_message: self.subscribe_message.clone(),
+            version: self.version.clone(),
+            host: self.host.clone(),
+            start: self.start.to_string(),
+        };
+        // Serializing plain strings cannot fail.
+        serde_json::to_string(&raw).unwrap()
+    }
+
+    /// The format version is checked before anything else as the other fields
+    /// may differ between versions.
+    pub fn parse_json(json: &str) -> Result<Header, Error> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        match value.get("format").and_then(|format| format.as_u64()) {
+            Some(format) if format == FORMAT_VERSION as u64 => (),
+            Some(format) => return Err(unsupported_version(format)),
+            None => return Err(Error::Protocol("capture header without format version".to_string())),
+        }
+        let raw: RawHeader = serde_json::from_value(value)?;
+        Ok(Header {
+            format: raw.format,
+            feed: Feed::of_str(&raw.feed)?,
+            products: raw.products,
+            server_name: raw.server_name,
+            subscribe_message: raw.subscribe_message,
+            version: raw.version,
+            host: raw.host,
+            start: Time::parse(&raw.start)?,
+        })
+    }
+
+    /// The header as the first line of a text capture.
+    pub fn to_line(&self) -> String {
+        format!("{}{}\n", HEADER_PREFIX, self.to_json())
+    }
+
+    /// Returns None if the line is not a header.
+    pub fn parse_line(line: &str) -> Result<Option<Header>, Error> {
+        if !line.starts_with(HEADER_PREFIX) {
+            return Ok(None)
+        }
+        Header::parse_json(line[HEADER_PREFIX.len()..].trim_end()).map(Some)
+    }
+}
+
+fn unsupported_version(format: u64) -> Error {
+    Error::Protocol(format!("unsupported capture format version {}, expected {}", format, FORMAT_VERSION))
+}
+
+/// The compression of a capture file, chosen from the file extension when writing
+/// and detected from the content when reading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// Plain files.
+    None,
+    /// Files ending with .gz.
+    Gzip,
+    /// Files ending with .zst.
+    Zstd,
+}
+
+impl Compression {
+    /// The compression for a filename, from its extension.
+    pub fn of_filename(filename: &str) -> Compression {
+        if filename.ends_with(".gz") {
+            Compression::Gzip
+        } else if filename.ends_with(".zst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// When to start a new capture file. The rotated files are named after the
+/// capture filename, e.g. gdax.log.gz becomes gdax-2018-01-17T21.log.gz when
+/// rotating hourly, gdax-2018-01-17.log.gz daily and gdax-000001.log.gz by size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    /// A single file.
+    Never,
+    /// The size is the number of uncompressed bytes written to a file.
+    Size(u64),
+    /// A file per hour of receive time.
+    Hourly,
+    /// A file per day of receive time.
+    Daily,
+}
+
+impl Rotation {
+    /// Parses never, hourly, daily or a size such as 500MB.
+    pub fn of_str(str: &str) -> Result<Rotation, Error> {
+        let size = |digits: &str, unit: u64| {
+            digits.parse::<u64>()
+                .ok()
+                .filter(|&size| size > 0)
+                .map(|size| Rotation::Size(size * unit))
+                .ok_or_else(|| Error::Parse(format!("invalid rotation {}", str)))
+        };
+        match str {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ if str.ends_with("GB") => size(&str[..str.len() - 2], 1 << 30),
+            _ if str.ends_with("MB") => size(&str[..str.len() - 2], 1 << 20),
+            _ if str.ends_with("KB") => size(&str[..str.len() - 2], 1 << 10),
+            _ => size(str, 1),
+        }
+    }
+
+    // The part of the rotated filenames that identifies the period of `time`.
+    fn period(self, time: &Time) -> Option<String> {
+        match self {
+            Rotation::Never | Rotation::Size(_) => None,
+            Rotation::Hourly => Some(time.format("%Y-%m-%dT%H")),
+            Rotation::Daily => Some(time.format("%Y-%m-%d")),
+        }
+    }
+}
+
+// Splits a filename into the part before the extensions and the extensions,
+// e.g. captures/gdax.log.gz gives captures/gdax and .log.gz.
+fn split_extension(filename: &str) -> (&str, &str) {
+    let start = filename.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match filename[start..].find('.') {
+        Some(dot) => filename.split_at(start + dot),
+        None => (filename, ""),
+    }
+}
+
+/// Inserts a suffix before the extensions, e.g. to give each product its own file.
+pub fn with_suffix(filename: &str, suffix: &str) -> String {
+    let (stem, extension) = split_extension(filename);
+    format!("{}-{}{}", stem, suffix, extension)
+}
+
+fn append(filename: &str) -> io::Result<File> {
+    fs::OpenOptions::new().create(true).append(true).open(filename)
+}
+
+// Whether a file exists and is not empty.
+fn has_content(filename: &str) -> io::Result<bool> {
+    match fs::metadata(filename) {
+        Ok(metadata) => Ok(metadata.len() > 0),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    // Appends to the file if it exists. This is refused for the compressed files
+    // as a stream cut off by a crash would make whatever follows it unreadable.
+    fn create(filename: &str, compression: Compression) -> io::Result<Encoder> {
+        let file = match compression {
+            Compression::None => append(filename)?,
+            _ => fs::OpenOptions::new().write(true).create_new(true).open(filename)?,
+        };
+        let file = BufWriter::new(file);
+        Ok(match compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            Encoder::Plain(ref mut writer) => writer,
+            Encoder::Gzip(ref mut writer) => writer,
+            Encoder::Zstd(ref mut writer) => writer,
+        }
+    }
+
+    // Completes the compressed stream and syncs the file to disk.
+    fn finish(self) -> io::Result<()> {
+        let writer = match self {
+            Encoder::Plain(writer) => writer,
+            Encoder::Gzip(writer) => writer.finish()?,
+            Encoder::Zstd(writer) => writer.finish()?,
+        };
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+}
+
+/// The layout of the records in a capture file, chosen from the file extension:
+/// .bin for binary, anything else for text, e.g. gdax.bin.zst is a compressed
+/// binary capture.
+///
+/// A text capture is made of lines, the receive time followed by the message.
+/// A binary capture starts with a preamble:
+///     magic "COINCAP\0", format version: u32, header length: u32, header json
+/// followed by the records, all integers being little endian:
+///     receive time in ns since the epoch: i64, feed id: u8, connection epoch: u64,
+///     payload length: u32, payload
+/// An uncompressed binary capture has a sparse time index in a file named after
+/// it with a .idx suffix, see Index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A line per record, readable with zcat and grep.
+    Text,
+    /// Length prefixed records, faster to read back.
+    Binary,
+}
+
+const BINARY_MAGIC: &[u8] = b"COINCAP\0";
+
+impl Format {
+    /// The format for a filename, binary when it has a .bin extension.
+    pub fn of_filename(filename: &str) -> Format {
+        let (_, extension) = split_extension(filename);
+        if extension == ".bin" || extension.starts_with(".bin.") {
+            Format::Binary
+        } else {
+            Format::Text
+        }
+    }
+}
+
+/// A message as captured.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// The receive time of the message.
+    pub time: Time,
+    /// None for the text captures written without a header.
+    pub feed: Option<Feed>,
+    /// The connection the message was received on, text captures do not keep it.
+    pub epoch: u64,
+    /// The message as received.
+    pub payload: String,
+}
+
+impl Record {
+    fn encode(&self, format: Format) -> Vec<u8> {
+        match format {
+            Format::Text => format!("{}{}\n", self.time, self.payload).into_bytes(),
+            Format::Binary => {
+                let mut bytes = Vec::with_capacity(21 + self.payload.len());
+                bytes.extend_from_slice(&self.time.to_nanos().to_le_bytes());
+                bytes.push(self.feed.map(|feed| feed.id()).unwrap_or(0));
+                bytes.extend_from_slice(&self.epoch.to_le_bytes());
+                bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(self.payload.as_bytes());
+                bytes
+            },
+        }
+    }
+}
+
+// The sparse time index of a binary capture, an entry is added whenever a
+// record is received at least a second after the last indexed one. Each entry
+// is the receive time in ns and the offset of the record in the capture, both
+// as 8 bytes little endian.
+struct Index {
+    file: BufWriter<File>,
+    last: Option<i64>,
+}
+
+const INDEX_INTERVAL: i64 = 1_000_000_000;
+
+fn index_filename(path: &str) -> String {
+    format!("{}.idx", path)
+}
+
+impl Index {
+    fn create(filename: &str) -> io::Result<Index> {
+        let file = BufWriter::new(append(&index_filename(filename))?);
+        Ok(Index { file, last: None })
+    }
+
+    fn add(&mut self, time: &Time, offset: u64) -> io::Result<()> {
+        let nanos = time.to_nanos();
+        if self.last.map(|last| nanos - last >= INDEX_INTERVAL).unwrap_or(true) {
+            self.file.write_all(&nanos.to_le_bytes())?;
+            self.file.write_all(&offset.to_le_bytes())?;
+            self.last = Some(nanos);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+
+    // The offset of the last indexed record received before `time`, reading
+    // from there finds all the records received from `time`.
+    fn seek_offset(filename: &Path, time: &Time) -> io::Result<Option<u64>> {
+        let mut bytes = Vec::new();
+        match File::open(index_filename(&filename.to_string_lossy())) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let nanos = time.to_nanos();
+        let mut offset = None;
+        for entry in bytes.chunks_exact(16) {
+            let (entry_nanos, entry_offset) = entry.split_at(8);
+            if i64::from_le_bytes(entry_nanos.try_into().unwrap()) >= nanos {
+                break
+            }
+            offset = Some(u64::from_le_bytes(entry_offset.try_into().unwrap()));
+        }
+        Ok(offset)
+    }
+}
+
+/// Writes capture records to a possibly compressed and rotated file. The files
+/// are completed and synced to disk when rotating and when dropped.
+pub struct CaptureWriter {
+    filename: String,
+    format: Format,
+    compression: Compression,
+    rotation: Rotation,
+    encoder: Option<Encoder>,
+    // Only kept for uncompressed binary captures, which can be seeked.
+    index: Option<Index>,
+    // Written at the top of each file, before its first record.
+    header: Option<Header>,
+    header_written: bool,
+    // The period or index of the current file, and the bytes written to it.
+    current: String,
+    written: u64,
+    file_index: u64,
+}
+
+impl CaptureWriter {
+    /// Creates or continues a capture, the format and compression follow the filename.
+    pub fn create(filename: &str, rotation: Rotation) -> io::Result<CaptureWriter> {
+        let mut writer = CaptureWriter {
+            filename: filename.to_string(),
+            format: Format::of_filename(filename),
+            compression: Compression::of_filename(filename),
+            rotation,
+            encoder: None,
+            index: None,
+            header: None,
+            header_written: false,
+            current: String::new(),
+            written: 0,
+            file_index: 0,
+        };
+        // Without rotation the file is created right away so that errors show early.
+        if rotation == Rotation::Never {
+            writer.open(filename)?;
+        }
+        if let Rotation::Size(max_size) = rotation {
+            writer.file_index = writer.last_index(max_size)?;
+        }
+        Ok(writer)
+    }
+
+    // The index of the size rotated file that precedes the next one to write to,
+    // so that a restart continues after the files already written. The last file
+    // is only appended to if it is neither compressed nor full.
+    fn last_index(&self, max_size: u64) -> io::Result<u64> {
+        let (index, path) = match parts(&self.filename)?.pop() {
+            Some((Part::Index(index), path)) => (index, path),
+            _ => return Ok(0),
+        };
+        let size = fs::metadata(path)?.len();
+        if self.compression == Compression::None && size < max_size {
+            Ok(index - 1)
+        } else {
+            Ok(index)
+        }
+    }
+
+    /// The header written at the top of each file from now on.
+    pub fn set_header(&mut self, header: Header) {
+        self.header = Some(header);
+    }
+
+    // The name of the file that the next record would be written to.
+    fn next_filename(&self, time: &Time) -> Option<String> {
+        match self.rotation {
+            Rotation::Never => None,
+            Rotation::Size(max_size) => {
+                if self.encoder.is_some() && self.written < max_size {
+                    None
+                } else {
+                    Some(with_suffix(&self.filename, &format!("{:06}", self.file_index + 1)))
+                }
+            },
+            Rotation::Hourly | Rotation::Daily => {
+                let period = self.rotation.period(time).unwrap_or_default();
+                if self.encoder.is_some() && period == self.current {
+                    None
+                } else {
+                    Some(self.period_filename(&period))
+                }
+            },
+        }
+    }
+
+    // A compressed file is not appended to, a restart within its period
+    // continues in a new file numbered after the period, e.g. -2018-01-17T21-1.
+    fn period_filename(&self, period: &str) -> String {
+        let mut filename = with_suffix(&self.filename, period);
+        let mut restart = 0;
+        while self.compression != Compression::None && has_content(&filename).unwrap_or(true) {
+            restart += 1;
+            filename = with_suffix(&self.filename, &format!("{}-{}", period, restart));
+        }
+        filename
+    }
+
+    // An uncompressed file that already exists, e.g. when restarting within the
+    // same period, is appended to. It already starts with a header, and the bytes
+    // it holds count towards the size rotation and the index offsets.
+    fn open(&mut self, filename: &str) -> io::Result<()> {
+        let existing = match fs::metadata(filename) {
+            Ok(metadata) => metadata.len(),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error),
+        };
+        if existing > 0 {
+            info!("appending to {}", filename);
+        }
+        self.encoder = Some(Encoder::create(filename, self.compression)?);
+        if self.format == Format::Binary && self.compression == Compression::None {
+            self.index = Some(Index::create(filename)?);
+        }
+        self.header_written = existing > 0;
+        self.written = existing;
+        Ok(())
+    }
+
+    fn rotate(&mut self, filename: &str, time: &Time) -> io::Result<()> {
+        self.finish()?;
+        info!("capturing to {}", filename);
+        self.open(filename)?;
+        self.current = self.rotation.period(time).unwrap_or_default();
+        self.file_index += 1;
+        Ok(())
+    }
+
+    // The header of a text capture, or the preamble of a binary one.
+    fn preamble(&self) -> Vec<u8> {
+        match self.format {
+            Format::Text => self.header.as_ref().map(|header| header.to_line().into_bytes()).unwrap_or_default(),
+            Format::Binary => {
+                let json = self.header.as_ref().map(|header| header.to_json()).unwrap_or_default();
+                let mut bytes = BINARY_MAGIC.to_vec();
+                bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+                bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(json.as_bytes());
+                bytes
+            },
+        }
+    }
+
+    /// Writes a record, its receive time also decides the file it goes to.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if let Some(filename) = self.next_filename(&record.time) {
+            self.rotate(&filename, &record.time)?;
+        }
+        if !self.header_written {
+            if let Some(ref mut header) = self.header {
+                header.start = record.time.clone();
+            }
+            let preamble = self.preamble();
+            self.write_bytes(&preamble)?;
+            self.header_written = true;
+        }
+        if let Some(ref mut index) = self.index {
+            index.add(&record.time, self.written)?;
+        }
+        self.write_bytes(&record.encode(self.format))
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.writer().write_all(bytes)?;
+        }
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Completes the current file and syncs it to disk.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(index) = self.index.take() {
+            index.finish()?;
+        }
+        match self.encoder.take() {
+            Some(encoder) => encoder.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            error!("unable to complete capture {}: {}", self.filename, error);
+        }
+    }
+}
+
+/// Opens a capture file for reading, decompressing it if needed.
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let magic = file.fill_buf()?.to_vec();
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file))))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        let decoder: Box<dyn Read> = Box::new(zstd::Decoder::with_buffer(file)?);
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+// Whether a string has a shape such as "dddd-dd-dd", where d stands for any digit.
+fn has_shape(str: &str, shape: &str) -> bool {
+    str.len() == shape.len() && str.chars().zip(shape.chars()).all(|(c, s)| {
+        if s == 'd' { c.is_ascii_digit() } else { c == s }
+    })
+}
+
+// The position of a rotated file in its capture, from the suffix given by the
+// writer: the file index for the size rotation, the period and the restart
+// within it otherwise.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Part {
+    Index(u64),
+    Period(String, u64),
+}
+
+impl Part {
+    fn of_suffix(suffix: &str) -> Option<Part> {
+        if suffix.len() >= 6 && suffix.chars().all(|c| c.is_ascii_digit()) {
+            return suffix.parse().ok().map(Part::Index)
+        }
+        for shape in ["dddd-dd-ddTdd", "dddd-dd-dd"].iter() {
+            if suffix.len() < shape.len() || !has_shape(&suffix[..shape.len()], shape) {
+                continue
+            }
+            let (period, restart) = suffix.split_at(shape.len());
+            if restart.is_empty() {
+                return Some(Part::Period(period.to_string(), 0))
+            }
+            if restart.len() > 1 && restart.starts_with('-') && restart[1..].chars().all(|c| c.is_ascii_digit()) {
+                return restart[1..].parse().ok().map(|restart| Part::Period(period.to_string(), restart))
+            }
+        }
+        None
+    }
+}
+
+/// The files making a capture, in chronological order. This is the file itself
+/// if it exists, otherwise the files it has been rotated to. Other files sharing
+/// the prefix, e.g. the per-product captures of gemini, are not part of it.
+pub fn files(filename: &str) -> io::Result<Vec<PathBuf>> {
+    let path = Path::new(filename);
+    if path.exists() {
+        return Ok(vec![path.to_path_buf()])
+    }
+    let files = parts(filename)?;
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no capture file for {}", filename)))
+    }
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+// The files a capture has been rotated to, in chronological order.
+fn parts(filename: &str) -> io::Result<Vec<(Part, PathBuf)>> {
+    let path = Path::new(filename);
+    let (stem, extension) = split_extension(filename);
+    let directory = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = format!("{}-", &stem[stem.rfind('/').map(|i| i + 1).unwrap_or(0)..]);
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.len() <= prefix.len() + extension.len() || !name.starts_with(&prefix) || !name.ends_with(extension) {
+            continue
+        }
+        if let Some(part) = Part::of_suffix(&name[prefix.len()..name.len() - extension.len()]) {
+            files.push((part, entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// The records of a single capture file.
+enum Source {
+    Text(Box<dyn BufRead>),
+    Binary(Box<dyn BufRead>),
+}
+
+// Reads exactly `buffer.len()` bytes, returns false at the end of the file.
+fn read_exact_or_eof(reader: &mut dyn BufRead, buffer: &mut [u8]) -> io::Result<bool> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(false)
+    }
+    reader.read_exact(buffer)?;
+    Ok(true)
+}
+
+impl Source {
+    // Opens a file and reads its header, seeking close to `from` when possible.
+    fn open(path: &Path, from: Option<&Time>) -> Result<(Source, Option<Header>), Error> {
+        let mut reader = open(path)?;
+        if !reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let header = Header::parse_line(&line)?;
+            // The first line is a message if the capture has no header.
+            let reader: Box<dyn BufRead> = if header.is_some() {
+                reader
+            } else {
+                Box::new(io::Cursor::new(line.into_bytes()).chain(reader))
+            };
+            return Ok((Source::Text(reader), header))
+        }
+        let mut preamble = [0; 16];
+        reader.read_exact(&mut preamble)?;
+        let format = u32::from_le_bytes(preamble[8..12].try_into().unwrap());
+        if format != FORMAT_VERSION {
+            return Err(unsupported_version(format as u64))
+        }
+        let mut json = vec![0; u32::from_le_bytes(preamble[12..16].try_into().unwrap()) as usize];
+        reader.read_exact(&mut json)?;
+        let header = if json.is_empty() {
+            None
+        } else {
+            Some(Header::parse_json(&String::from_utf8_lossy(&json))?)
+        };
+        if let Some(time) = from {
+            if let Some(offset) = Index::seek_offset(path, time)? {
+                // The index is only written for uncompressed captures.
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                reader = Box::new(BufReader::new(file));
+            }
+        }
+        Ok((Source::Binary(reader), header))
+    }
+
+    fn next(&mut self, feed: Option<Feed>) -> Result<Option<Record>, Error> {
+        match *self {
+            Source::Text(ref mut reader) => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 {
+                        return Ok(None)
+                    }
+                    let line = line.trim_end_matches('\n');
+                    // A malformed line is skipped, the rest of the file is still usable.
+                    match line.get(..::time::LEN).map(Time::parse) {
+                        Some(Ok(time)) => {
+                            return Ok(Some(Record { time, feed, epoch: 1, payload: line[::time::LEN..].to_string() }))
+                        },
+                        Some(Err(error)) => error!("skipping line: {}", error.in_message(line)),
+                        None => error!("skipping truncated line {}", line),
+                    }
+                }
+            },
+            Source::Binary(ref mut reader) => {
+                let mut fixed = [0; 21];
+                if !read_exact_or_eof(&mut **reader, &mut fixed)? {
+                    return Ok(None)
+                }
+                let mut payload = vec![0; u32::from_le_bytes(fixed[17..21].try_into().unwrap()) as usize];
+                reader.read_exact(&mut payload)?;
+                let feed = match fixed[8] {
+                    0 => None,
+                    id => Some(Feed::of_id(id)?),
+                };
+                Ok(Some(Record {
+                    time: Time::of_nanos(i64::from_le_bytes(fixed[0..8].try_into().unwrap())),
+                    feed,
+                    epoch: u64::from_le_bytes(fixed[9..17].try_into().unwrap()),
+                    payload: String::from_utf8(payload).map_err(|e| Error::Parse(e.to_string()))?,
+                }))
+            },
+        }
+    }
+}
+
+/// Reads the records of a text or binary capture, possibly compressed and
+/// rotated, optionally restricted to a range of receive times.
+pub struct CaptureReader {
+    files: VecDeque<PathBuf>,
+    source: Option<Source>,
+    header: Option<Header>,
+    from: Option<Time>,
+    to: Option<Time>,
+}
+
+impl CaptureReader {
+    /// Opens a capture and its rotated parts, the header of the first part is read right away.
+    pub fn open(filename: &str) -> Result<CaptureReader, Error> {
+        let files: VecDeque<_> = files(filename)?.into_iter().collect();
+        // The header of the first file is read right away, the files are only
+        // opened for reading once the range is known.
+        let (_, header) = Source::open(&files[0], None)?;
+        Ok(CaptureReader { files, source: None, header, from: None, to: None })
+    }
+
+    /// The header of the file being read.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Only returns the records received from `from` up to `to`, both included.
+    /// The records are expected to be in receive time order, the reading stops
+    /// at the first record received after `to`.
+    pub fn with_range(mut self, from: Option<Time>, to: Option<Time>) -> Result<CaptureReader, Error> {
+        if let Some(ref from) = from {
+            // The rotated files that end before `from` are skipped, the index
+            // is then used to seek within the first file read.
+            while self.files.len() > 1 {
+                match Source::open(&self.files[1], None)?.1 {
+                    Some(ref header) if header.start <= *from => self.files.pop_front(),
+                    _ => break,
+                };
+            }
+        }
+        self.from = from;
+        self.to = to;
+        Ok(self)
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        loop {
+            if self.source.is_none() {
+                let path = match self.files.pop_front() {
+                    Some(path) => path,
+                    None => return Ok(None),
+                };
+                let (source, header) = Source::open(&path, self.from.as_ref())?;
+                // A rotated file without header is part of the same capture.
+                self.header = header.or_else(|| self.header.take());
+                self.source = Some(source);
+            }
+            let feed = self.header.as_ref().map(|header| header.feed);
+            let record = match self.source {
+                Some(ref mut source) => source.next(feed)?,
+                None => None,
+            };
+            let record = match record {
+                Some(record) => record,
+                None => {
+                    self.source = None;
+                    continue
+                },
+            };
+            if self.from.as_ref().map(|from| record.time < *from).unwrap_or(false) {
+                continue
+            }
+            if self.to.as_ref().map(|to| record.time > *to).unwrap_or(false) {
+                self.files.clear();
+                self.source = None;
+                return Ok(None)
+            }
+            return Ok(Some(record))
+        }
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Result<Record, Error>> {
+        match self.next_record() {
+            Ok(record) => record.map(Ok),
+            Err(error) => {
+                // A broken file stops the reading.
+                self.files.clear();
+                self.source = None;
+                Some(Err(error))
+            },
+        }
+    }
+}
+
+/// Merges the records of several captures by receive time, e.g. to replay the
+/// feeds recorded in separate files together. Each record comes with the index
+/// of the capture it was read from, the records received at the same time are
+/// returned in the order of the captures.
+pub struct MergeReader {
+    readers: Vec<CaptureReader>,
+    // The next record of each capture, and the order in which they come.
+    pending: Vec<Option<Record>>,
+    order: BinaryHeap<Reverse<(Time, usize)>>,
+    started: bool,
+}
+
+impl MergeReader {
+    /// Merges the readers, the records are tagged with their index in `readers`.
+    pub fn new(readers: Vec<CaptureReader>) -> MergeReader {
+        let pending = readers.iter().map(|_| None).collect();
+        MergeReader { readers, pending, order: BinaryHeap::new(), started: false }
+    }
+
+    /// The headers of the captures, in the order they were given.
+    pub fn headers(&self) -> Vec<Option<&Header>> {
+        self.readers.iter().map(|reader| reader.header()).collect()
+    }
+
+    fn fill(&mut self, source: usize) -> Result<(), Error> {
+        if let Some(record) = self.readers[source].next() {
+            let record = record?;
+            self.order.push(Reverse((record.time.clone(), source)));
+            self.pending[source] = Some(record);
+        }
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<(usize, Record)>, Error> {
+        if !self.started {
+            self.started = true;
+            for source in 0..self.readers.len() {
+                self.fill(source)?;
+            }
+        }
+        let source = match self.order.pop() {
+            Some(Reverse((_, source))) => source,
+            None => return Ok(None),
+        };
+        let record = self.pending[source].take().unwrap();
+        self.fill(source)?;
+        Ok(Some((source, record)))
+    }
+}
+
+impl Iterator for MergeReader {
+    type Item = Result<(usize, Record), Error>;
+
+    fn next(&mut self) -> Option<Result<(usize, Record), Error>> {
+        match self.next_record() {
+            Ok(record) => record.map(Ok),
+            Err(error) => {
+                self.order.clear();
+                Some(Err(error))
+            },
+        }
+    }
+}
+
+/// The header of a capture, None if it was written without one.
+pub fn read_header(filename: &str) -> Result<Option<Header>, Error> {
+    Ok(CaptureReader::open(filename)?.header)
+}
+
+/// Copies a capture to another one, converting between the text and binary
+/// formats and compressions according to the filenames. Returns the number of
+/// records copied.
+pub fn convert(input: &str, output: &str, rotation: Rotation) -> Result<u64, Error> {
+    // The writer would append to an existing capture.
+    if files(output).is_ok() {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", output))))
+    }
+    let reader = CaptureReader::open(input)?;
+    let mut writer = CaptureWriter::create(output, rotation)?;
+    if let Some(header) = reader.header() {
+        writer.set_header(header.clone());
+    }
+    let mut count = 0;
+    for record in reader {
+        writer.write(&record?)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::temp_directory;
+
+    fn lines(path: &Path) -> Vec<String> {
+        open(path).unwrap().lines().map(|line| line.unwrap()).collect()
+    }
+
+    #[test]
+    fn header_test() {
+        let endpoint = Endpoint { server_name: "wss://ws-feed.gdax.com".to_string(), subscribe_message: Some("{}".to_string()) };
+        let mut header = Header::new(Feed::Gdax, vec!["BTC-USD".to_string()], &endpoint);
+        header.start = Time::parse("2018-01-17 21:00:00.000000000").unwrap();
+        let line = header.to_line();
+        assert!(line.starts_with("#coin {\"format\":1,\"feed\":\"gdax\""));
+        assert_eq!(Header::parse_line(&line), Ok(Some(header)));
+        assert_eq!(Header::parse_line("2018-01-17 21:00:00.000000000{}"), Ok(None));
+        match Header::parse_line("#coin {\"format\":2,\"feed\":\"kraken\"}") {
+            Err(Error::Protocol(message)) => assert!(message.contains("version 2")),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    fn record(time: &str, epoch: u64, payload: &str) -> Record {
+        Record { time: Time::parse(time).unwrap(), feed: Some(Feed::Gdax), epoch, payload: payload.to_string() }
+    }
+
+    #[test]
+    fn rotation_test() {
+        assert_eq!(Rotation::of_str("hourly"), Ok(Rotation::Hourly));
+        assert_eq!(Rotation::of_str("2MB"), Ok(Rotation::Size(2 << 20)));
+        assert!(Rotation::of_str("weekly").is_err());
+        assert_eq!(with_suffix("captures/gdax.log.gz", "btcusd"), "captures/gdax-btcusd.log.gz");
+        assert_eq!(with_suffix("gdax", "000001"), "gdax-000001");
+
+        let directory = temp_directory("rotation");
+        let filename = directory.join("gdax.log.gz").to_string_lossy().into_owned();
+        {
+            let mut writer = CaptureWriter::create(&filename, Rotation::Hourly).unwrap();
+            for time in ["2018-01-17 21:59:59.000000000", "2018-01-17 22:00:00.000000000", "2018-01-17 22:30:00.000000000"].iter() {
+                writer.write(&record(time, 1, "{}")).unwrap();
+            }
+        }
+        // The per-product captures and other files sharing the prefix are left out.
+        for name in ["gdax-btcusd.log.gz", "gdax-ethusd-2018-01-17T21.log.gz", "gdax-2018-01-17T21.log"].iter() {
+            fs::write(directory.join(name), "").unwrap();
+        }
+        let parts = files(&filename).unwrap();
+        let names: Vec<_> = parts.iter().map(|file| file.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["gdax-2018-01-17T21.log.gz", "gdax-2018-01-17T22.log.gz"]);
+        assert_eq!(lines(&parts[0]), vec!["2018-01-17 21:59:59.000000000{}"]);
+        assert_eq!(lines(&parts[1]).len(), 2);
+        let times: Vec<_> = CaptureReader::open(&filename).unwrap().map(|record| record.unwrap().time.to_string()).collect();
+        assert_eq!(times.len(), 3);
+        assert_eq!(times[2], "2018-01-17 22:30:00.000000000");
+
+        let filename = directory.join("gemini.log.zst").to_string_lossy().into_owned();
+        {
+            let mut writer = CaptureWriter::create(&filename, Rotation::Size(40)).unwrap();
+            for payload in ["0123456789", "abc", "def"].iter() {
+                writer.write(&record("1970-01-01 00:00:00.000000000", 1, payload)).unwrap();
+            }
+        }
+        let parts = files(&filename).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(lines(&parts[1]), vec!["1970-01-01 00:00:00.000000000abc", "1970-01-01 00:00:00.000000000def"]);
+        // The file indexes are ordered as numbers.
+        assert!(Part::of_suffix("999999") < Part::of_suffix("1000000"));
+        assert!(Part::of_suffix("2018-01-17T2").is_none());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn append_test() {
+        let directory = temp_directory("append");
+        let endpoint = Endpoint { server_name: "wss://ws-feed.gdax.com".to_string(), subscribe_message: None };
+        let write = |filename: &str, rotation, records: &[Record]| {
+            let mut writer = CaptureWriter::create(filename, rotation).unwrap();
+            writer.set_header(Header::new(Feed::Gdax, vec!["BTC-USD".to_string()], &endpoint));
+            for record in records {
+                writer.write(record).unwrap();
+            }
+        };
+        let records = vec![
+            record("2018-01-17 21:00:00.000000000", 1, "{}"),
+            record("2018-01-17 21:00:05.000000000", 1, "{}"),
+            record("2018-01-17 21:30:00.000000000", 1, "{}"),
+            record("2018-01-17 22:00:00.000000000", 1, "{}"),
+        ];
+        // Restarting within the same hour keeps what has been captured so far,
+        // an uncompressed file is appended to.
+        let filename = directory.join("gdax.log").to_string_lossy().into_owned();
+        write(&filename, Rotation::Hourly, &records[..2]);
+        write(&filename, Rotation::Hourly, &records[2..]);
+        let parts = files(&filename).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(lines(&parts[0]).len(), 4);
+        assert!(lines(&parts[0])[0].starts_with(HEADER_PREFIX));
+        let read: Vec<_> = CaptureReader::open(&filename).unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(read, records);
+
+        // A compressed one is left as is, the restart goes to a new file of the period.
+        let filename = directory.join("gdax.log.gz").to_string_lossy().into_owned();
+        write(&filename, Rotation::Hourly, &records[..2]);
+        write(&filename, Rotation::Hourly, &records[2..]);
+        let parts = files(&filename).unwrap();
+        let names: Vec<_> = parts.iter().map(|file| file.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["gdax-2018-01-17T21.log.gz", "gdax-2018-01-17T21-1.log.gz", "gdax-2018-01-17T22.log.gz"]);
+        assert!(lines(&parts[1])[0].starts_with(HEADER_PREFIX));
+        let read: Vec<_> = CaptureReader::open(&filename).unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(read, records);
+        let compressed = directory.join("copy.log.gz").to_string_lossy().into_owned();
+        write(&compressed, Rotation::Never, &records[..1]);
+        assert!(CaptureWriter::create(&compressed, Rotation::Never).is_err());
+
+        // The index offsets account for the records already in the file.
+        let filename = directory.join("gdax.bin").to_string_lossy().into_owned();
+        write(&filename, Rotation::Never, &records[..2]);
+        write(&filename, Rotation::Never, &records[2..]);
+        let from = Some(Time::parse("2018-01-17 21:30:00.000000000").unwrap());
+        let read: Vec<_> = CaptureReader::open(&filename).unwrap().with_range(from, None).unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(read, records[2..].to_vec());
+        assert!(convert(&filename, &filename, Rotation::Never).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn size_restart_test() {
+        let directory = temp_directory("size-restart");
+        let write = |filename: &str, seconds: &[&str]| {
+            let mut writer = CaptureWriter::create(filename, Rotation::Size(40)).unwrap();
+            for second in seconds {
+                writer.write(&record(&format!("2018-01-17 21:00:{}.000000000", second), 1, "{}")).unwrap();
+            }
+        };
+        // Each record takes 31 bytes, a file holds two of them.
+        for name in ["gdax.log", "gdax.log.zst"].iter() {
+            let filename = directory.join(name).to_string_lossy().into_owned();
+            write(&filename, &["01", "02", "03"]);
+            write(&filename, &["10", "11"]);
+            let times: Vec<_> = CaptureReader::open(&filename).unwrap()
+                .map(|record| record.unwrap().time.format("%S"))
+                .collect();
+            assert_eq!(times, vec!["01", "02", "03", "10", "11"]);
+            // The last file is only appended to when uncompressed.
+            let sizes: Vec<_> = files(&filename).unwrap().iter().map(|part| lines(part).len()).collect();
+            assert_eq!(sizes, if name.ends_with(".zst") { vec![2, 1, 2] } else { vec![2, 2, 1] });
+        }
+        assert!(Part::of_suffix("2018-01-17T21-1") > Part::of_suffix("2018-01-17T21"));
+        assert!(Part::of_suffix("2018-01-17T21-1") < Part::of_suffix("2018-01-17T22"));
+        assert!(Part::of_suffix("2018-01-17-").is_none());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn binary_test() {
+        let directory = temp_directory("binary");
+        let filename = directory.join("gdax.bin").to_string_lossy().into_owned();
+        let records = vec![
+            record("2018-01-17 14:29:59.000000000", 1, "{\"type\":\"heartbeat\"}"),
+            record("2018-01-17 14:29:59.500000000", 1, "{}"),
+            record("2018-01-17 14:30:01.000000000", 2, "{}"),
+            record("2018-01-17 14:30:02.000000000", 2, ""),
+        ];
+        let endpoint = Endpoint { server_name: "wss://ws-feed.gdax.com".to_string(), subscribe_message: None };
+        {
+            let mut writer = CaptureWriter::create(&filename, Rotation::Never).unwrap();
+            writer.set_header(Header::new(Feed::Gdax, vec!["BTC-USD".to_string()], &endpoint));
+            for record in records.iter() {
+                writer.write(record).unwrap();
+            }
+        }
+        // The records of 14:29:59 share an index entry, the others are a second apart.
+        assert_eq!(fs::metadata(index_filename(&filename)).unwrap().len(), 48);
+        let read = |from: Option<&str>, to: Option<&str>| -> Vec<Record> {
+            let start = Time::parse("2018-01-17 00:00:00.000000000").unwrap();
+            let from = from.map(|from| start.at_time_of_day(from).unwrap());
+            let to = to.map(|to| start.at_time_of_day(to).unwrap());
+            CaptureReader::open(&filename).unwrap().with_range(from, to).unwrap().map(|record| record.unwrap()).collect()
+        };
+        assert_eq!(read(None, None), records);
+        assert_eq!(read(Some("14:30"), None), records[2..].to_vec());
+        assert_eq!(read(Some("14:29:59.2"), Some("14:30:01")), records[1..3].to_vec());
+        assert_eq!(read_header(&filename).unwrap().unwrap().start, records[0].time);
+
+        // Text captures do not keep the epochs.
+        let text = directory.join("gdax.log.gz").to_string_lossy().into_owned();
+        assert_eq!(convert(&filename, &text, Rotation::Never), Ok(4));
+        let converted: Vec<_> = CaptureReader::open(&text).unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(converted[2], Record { epoch: 1, ..records[2].clone() });
+        let binary = directory.join("copy.bin.zst").to_string_lossy().into_owned();
+        assert_eq!(convert(&text, &binary, Rotation::Never), Ok(4));
+        assert_eq!(CaptureReader::open(&binary).unwrap().header().map(|header| header.feed), Some(Feed::Gdax));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn merge_test() {
+        let directory = temp_directory("merge");
+        let write = |name: &str, feed: Feed, times: &[&str]| -> String {
+            let filename = directory.join(name).to_string_lossy().into_owned();
+            let endpoint = Endpoint { server_name: "ws://127.0.0.1".to_string(), subscribe_message: None };
+            let mut writer = CaptureWriter::create(&filename, Rotation::Never).unwrap();
+            writer.set_header(Header::new(feed, vec![], &endpoint));
+            for time in times {
+                writer.write(&Record { feed: Some(feed), ..record(&format!("2018-01-17 21:00:{}", time), 1, name) }).unwrap();
+            }
+            filename
+        };
+        let gdax = write("gdax.bin", Feed::Gdax, &["00.000000000", "02.000000000", "03.000000000"]);
+        let gemini = write("gemini.log", Feed::Gemini, &["01.000000000", "02.000000000", "04.000000000"]);
+        let readers = vec![CaptureReader::open(&gdax).unwrap(), CaptureReader::open(&gemini).unwrap()];
+        let merged: Vec<_> = MergeReader::new(readers)
+            .map(|record| record.map(|(source, record)| (source, record.feed.unwrap(), record.time.format("%S"))).unwrap())
+            .collect();
+        assert_eq!(merged, vec![
+            (0, Feed::Gdax, "00".to_string()),
+            (1, Feed::Gemini, "01".to_string()),
+            (0, Feed::Gdax, "02".to_string()),
+            (1, Feed::Gemini, "02".to_string()),
+            (0, Feed::Gdax, "03".to_string()),
+            (1, Feed::Gemini, "04".to_string()),
+        ]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono;
+
+use error::Error;
+use shutdown::Shutdown;
+use time::Time;
+
+/// The source of the receive timestamps, shared by all the feeds of a process.
+pub trait Clock: Send + Sync {
+    /// The current time according to the clock.
+    fn now(&self) -> Time;
+}
+
+/// The wall clock, used by the live feeds.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Time {
+        Time::now()
+    }
+}
+
+/// How fast a capture is replayed compared to how it was recorded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    /// 1.0 replays at the recorded pace, 10.0 ten times faster.
+    Scaled(f64),
+    /// Does not wait between messages, the clock still jumps by the recorded gaps.
+    Max,
+}
+
+impl Speed {
+    /// The slowest and fastest paces, beyond these the waits are not meaningful.
+    pub const MIN_FACTOR: f64 = 0.001;
+    /// See MIN_FACTOR.
+    pub const MAX_FACTOR: f64 = 1_000_000.0;
+
+    /// A pace relative to the recorded one, between MIN_FACTOR and MAX_FACTOR.
+    pub fn scaled(factor: f64) -> Result<Speed, Error> {
+        if !(Speed::MIN_FACTOR..=Speed::MAX_FACTOR).contains(&factor) {
+            return Err(Error::Parse(format!("invalid replay speed {}, expected a factor between {} and {}", factor, Speed::MIN_FACTOR, Speed::MAX_FACTOR)))
+        }
+        Ok(Speed::Scaled(factor))
+    }
+
+    /// Checks the factor of a speed built directly.
+    pub fn validate(self) -> Result<Speed, Error> {
+        match self {
+            Speed::Scaled(factor) => Speed::scaled(factor),
+            Speed::Max => Ok(Speed::Max),
+        }
+    }
+
+    /// Parses max or a factor such as 1, 10 or 0.5.
+    pub fn of_str(str: &str) -> Result<Speed, Error> {
+        if str == "max" {
+            return Ok(Speed::Max)
+        }
+        match str.trim_end_matches('x').parse::<f64>() {
+            Ok(factor) => Speed::scaled(factor),
+            Err(_) => Err(Error::Parse(format!("invalid replay speed {}", str))),
+        }
+    }
+}
+
+// Scales a duration by a factor, None if the factor is invalid or the result
+// out of range.
+fn scale(duration: Duration, factor: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(duration.as_secs_f64() * factor).ok()
+}
+
+struct ReplayState {
+    // The recorded time of the first message and the instant it was replayed at.
+    origin: Option<(Time, Instant)>,
+    // The recorded time of the last message replayed.
+    last: Time,
+}
+
+/// The clock of a replay, it follows the receive times of the replayed messages
+/// so that the logic depending on the current time behaves as it did live.
+/// The replay waits on the clock before delivering each message.
+pub struct ReplayClock {
+    speed: Speed,
+    shutdown: Shutdown,
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayClock {
+    /// A clock that starts at the first message replayed.
+    pub fn new(speed: Speed) -> ReplayClock {
+        ReplayClock {
+            speed,
+            shutdown: Shutdown::new(),
+            state: Mutex::new(ReplayState { origin: None, last: Time::epoch() }),
+        }
+    }
+
+    /// The waits are interrupted when the shutdown is requested.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> ReplayClock {
+        ReplayClock { shutdown, ..self }
+    }
+
+    /// How fast the replay goes.
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Waits until a message received at `time` is due, returns false if the
+    /// shutdown has been requested meanwhile.
+    pub fn advance_to(&self, time: &Time) -> bool {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.last = time.clone();
+            match (self.speed, &state.origin) {
+                (Speed::Max, _) => None,
+                (Speed::Scaled(_), None) => {
+                    state.origin = Some((time.clone(), Instant::now()));
+                    None
+                },
+                (Speed::Scaled(factor), &Some((ref origin, instant))) => {
+                    // The messages recorded out of order are delivered right away,
+                    // as are the ones due beyond what an instant can represent.
+                    let elapsed = time.signed_duration_since(origin).to_std().unwrap_or_default();
+                    scale(elapsed, 1.0 / factor).and_then(|elapsed| instant.checked_add(elapsed))
+                },
+            }
+        };
+        match due {
+            Some(due) => !self.shutdown.wait(due.saturating_duration_since(Instant::now())),
+            None => !self.shutdown.is_requested(),
+        }
+    }
+}
+
+impl Clock for ReplayClock {
+    // Between messages the time flows at the replay speed, except when
+    // replaying as fast as possible where it is the time of the last message.
+    fn now(&self) -> Time {
+        let state = self.state.lock().unwrap();
+        match (self.speed, &state.origin) {
+            (Speed::Scaled(factor), &Some((ref origin, instant))) => {
+                // The time stops at the last message if it would go out of range.
+                scale(instant.elapsed(), factor)
+                    .and_then(|elapsed| chrono::Duration::from_std(elapsed).ok())
+                    .and_then(|elapsed| origin.checked_add(elapsed))
+                    .unwrap_or_else(|| state.last.clone())
+            },
+            _ => state.last.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(str: &str) -> Time {
+        Time::parse(str).unwrap()
+    }
+
+    #[test]
+    fn replay_clock_test() {
+        assert_eq!(Speed::of_str("10x"), Ok(Speed::Scaled(10.0)));
+        assert_eq!(Speed::of_str("max"), Ok(Speed::Max));
+        assert!(Speed::of_str("0").is_err());
+        assert!(Speed::of_str("-1").is_err());
+        assert!(Speed::of_str("NaN").is_err());
+        assert!(Speed::of_str("1e-300").is_err());
+        assert!(Speed::of_str("1e300").is_err());
+        assert!(Speed::Scaled(0.0).validate().is_err());
+        assert_eq!(Speed::Scaled(0.5).validate(), Ok(Speed::Scaled(0.5)));
+
+        // A speed built directly is not checked, the clock still does not panic.
+        for &factor in &[0.0, -1.0, f64::NAN, 1e-300, 1e300] {
+            let clock = ReplayClock::new(Speed::Scaled(factor));
+            assert!(clock.advance_to(&time("2018-01-17 21:00:00.000000000")));
+            assert!(clock.advance_to(&time("2018-01-17 21:00:01.000000000")));
+            clock.now();
+        }
+
+        let clock = ReplayClock::new(Speed::Max);
+        assert!(clock.advance_to(&time("2018-01-17 21:00:00.000000000")));
+        assert!(clock.advance_to(&time("2018-01-17 21:00:05.000000000")));
+        assert_eq!(clock.now(), time("2018-01-17 21:00:05.000000000"));
+
+        // A second at 1000x takes a millisecond.
+        let clock = ReplayClock::new(Speed::Scaled(1000.0));
+        let start = Instant::now();
+        assert!(clock.advance_to(&time("2018-01-17 21:00:00.000000000")));
+        assert!(clock.advance_to(&time("2018-01-17 21:00:01.000000000")));
+        assert!(start.elapsed() >= Duration::from_millis(1));
+        assert!(clock.now() >= time("2018-01-17 21:00:01.000000000"));
+
+        let shutdown = Shutdown::new();
+        let clock = ReplayClock::new(Speed::Scaled(1.0)).with_shutdown(shutdown.clone());
+        assert!(clock.advance_to(&time("2018-01-17 21:00:00.000000000")));
+        shutdown.request();
+        assert!(!clock.advance_to(&time("2018-01-17 22:00:00.000000000")));
+    }
+}
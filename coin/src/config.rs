@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_path_to_error;
+use toml;
+
+use arbitrage::{ArbitrageDetector, Opportunity};
+use capture::Rotation;
+use clock::Clock;
+use error::Error;
+use event::{EventSink, Feed, FeedEvent};
+use feeds::FeedSpec;
+use product::{ProductSpec, Registry};
+use supervisor::Backoff;
+
+// The configuration file as written, e.g.
+//
+// [[feeds]]
+// feed = "gdax"
+// products = ["BTC-USD", "ETH-USD"]
+// endpoint = "ws://127.0.0.1:8080"
+// stale_after_ms = 1000
+// log = "gdax.log.gz"
+// log_rotation = "hourly"
+//
+// [backoff]
+// initial_ms = 500
+// max_ms = 60000
+// multiplier = 2.0
+// jitter = 0.2
+//
+// [[products]]
+// product = "ETH-BTC"
+// tick_size = "0.00001"
+// lot_size = "0.00000001"
+// strict = true
+//
+// [[sinks]]
+// type = "arbitrage"
+// products = { gdax = "BTC-USD", gemini = "btcusd" }
+// taker_fees = { gdax = 0.0025, gemini = 0.0035 }
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    feeds: Vec<RawFeed>,
+    #[serde(default)]
+    products: Vec<RawProduct>,
+    #[serde(default)]
+    sinks: Vec<RawSink>,
+    backoff: Option<RawBackoff>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBackoff {
+    initial_ms: Option<u64>,
+    max_ms: Option<u64>,
+    multiplier: Option<f64>,
+    jitter: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProduct {
+    product: String,
+    tick_size: String,
+    lot_size: String,
+    #[serde(default)]
+    strict: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFeed {
+    feed: String,
+    products: Option<Vec<String>>,
+    endpoint: Option<String>,
+    stale_after_ms: Option<u64>,
+    log: Option<String>,
+    log_rotation: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum RawSink {
+    Events,
+    Arbitrage {
+        products: BTreeMap<String, String>,
+        #[serde(default)]
+        taker_fees: BTreeMap<String, f64>,
+        depth: Option<usize>,
+    },
+}
+
+/// The consumers of the events published by the feeds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkSpec {
+    /// Logs every event at debug level.
+    Events,
+    /// Logs the crossed markets between venues.
+    Arbitrage {
+        /// The product of each venue, as used in the events.
+        products: Vec<(Feed, String)>,
+        /// The taker fee of each venue, as a fraction of the traded notional.
+        taker_fees: Vec<(Feed, f64)>,
+        /// The number of levels of each book that are considered.
+        depth: usize,
+    },
+}
+
+/// A validated configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// The feeds to connect to.
+    pub feeds: Vec<FeedSpec>,
+    /// The builtin product specs, overridden by the configured ones.
+    pub products: Registry,
+    /// The consumers of the events of all the feeds.
+    pub sinks: Vec<SinkSpec>,
+}
+
+fn invalid<T>(key: String, message: String) -> Result<T, Error> {
+    Err(Error::Config { key, message })
+}
+
+fn feed(key: String, name: &str) -> Result<Feed, Error> {
+    Feed::of_str(name).or_else(|_| invalid(key, format!("unsupported feed {}", name)))
+}
+
+impl Config {
+    /// Reads and validates a configuration file.
+    pub fn load(filename: &str) -> Result<Config, Error> {
+        let content = fs::read_to_string(filename)?;
+        Config::parse(&content)
+    }
+
+    /// Validates a configuration, the errors name the key or the position at fault.
+    pub fn parse(content: &str) -> Result<Config, Error> {
+        let deserializer = toml::Deserializer::new(content);
+        let raw: RawConfig = serde_path_to_error::deserialize(deserializer).map_err(|error| {
+            // Type errors are located by their key, syntax errors by their position
+            // in the file.
+            let path = error.path().to_string();
+            let error = error.into_inner();
+            let key = match (path.as_str(), error.span()) {
+                (".", Some(span)) => {
+                    let before = &content[..span.start];
+                    let line = before.matches('\n').count() + 1;
+                    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+                    format!("line {} column {}", line, column)
+                },
+                (".", None) => "top level".to_string(),
+                _ => path,
+            };
+            Error::Config { key, message: error.message().to_string() }
+        })?;
+        if raw.feeds.is_empty() {
+            return invalid("feeds".to_string(), "at least one feed is required".to_string())
+        }
+        let backoff = match raw.backoff {
+            Some(ref raw_backoff) => Config::backoff("backoff", raw_backoff)?,
+            None => Backoff::default(),
+        };
+        let feeds = raw.feeds.iter().enumerate()
+            .map(|(index, raw_feed)| Config::feed_spec(&format!("feeds[{}]", index), raw_feed, &backoff))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut products = Registry::default();
+        for (index, raw_product) in raw.products.iter().enumerate() {
+            let key = format!("products[{}]", index);
+            let mut spec = ProductSpec::of_strs(&raw_product.tick_size, &raw_product.lot_size)
+                .or_else(|error| invalid(key.clone(), error.to_string()))?;
+            if spec.tick_size.to_float() <= 0.0 || spec.lot_size.is_zero() {
+                return invalid(key, "the tick and lot sizes must be positive".to_string())
+            }
+            spec.strict = raw_product.strict;
+            products.insert(&raw_product.product, spec);
+        }
+        let sinks = raw.sinks.iter().enumerate()
+            .map(|(index, raw_sink)| Config::sink_spec(&format!("sinks[{}]", index), raw_sink, &feeds))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Config { feeds, products, sinks })
+    }
+
+    // The unset values are the default ones.
+    fn backoff(key: &str, raw: &RawBackoff) -> Result<Backoff, Error> {
+        let mut backoff = Backoff::default();
+        if let Some(initial_ms) = raw.initial_ms {
+            if initial_ms == 0 {
+                return invalid(format!("{}.initial_ms", key), "must be positive".to_string())
+            }
+            backoff.initial = Duration::from_millis(initial_ms);
+        }
+        if let Some(max_ms) = raw.max_ms {
+            backoff.max = Duration::from_millis(max_ms);
+        }
+        if backoff.max < backoff.initial {
+            return invalid(format!("{}.max_ms", key), format!("must be at least the initial delay of {}ms", backoff.initial.as_millis()))
+        }
+        if let Some(multiplier) = raw.multiplier {
+            if !(multiplier.is_finite() && multiplier >= 1.0) {
+                return invalid(format!("{}.multiplier", key), format!("multiplier {} is less than 1", multiplier))
+            }
+            backoff.multiplier = multiplier;
+        }
+        if let Some(jitter) = raw.jitter {
+            if !(0.0..1.0).contains(&jitter) {
+                return invalid(format!("{}.jitter", key), format!("jitter {} is not a fraction", jitter))
+            }
+            backoff.jitter = jitter;
+        }
+        Ok(backoff)
+    }
+
+    fn feed_spec(key: &str, raw: &RawFeed, backoff: &Backoff) -> Result<FeedSpec, Error> {
+        let feed = feed(format!("{}.feed", key), &raw.feed)?;
+        let products = raw.products.clone().unwrap_or_else(|| FeedSpec::default_products(feed));
+        if products.is_empty() {
+            return invalid(format!("{}.products", key), "at least one product is required".to_string())
+        }
+        if let Some(index) = products.iter().position(|product| product.is_empty()) {
+            return invalid(format!("{}.products[{}]", key, index), "empty product".to_string())
+        }
+        let mut spec = FeedSpec::new(feed, products);
+        if let Some(ref endpoint) = raw.endpoint {
+            if !endpoint.starts_with("ws://") && !endpoint.starts_with("wss://") {
+                return invalid(format!("{}.endpoint", key), format!("not a websocket url {}", endpoint))
+            }
+            // The gemini connections of each symbol have their own url.
+            if spec.connections().len() > 1 {
+                return invalid(format!("{}.endpoint", key), "a gemini endpoint serves a single symbol, use a feed per symbol".to_string())
+            }
+            spec.server_name = Some(endpoint.clone());
+        }
+        if let Some(stale_after_ms) = raw.stale_after_ms {
+            if stale_after_ms == 0 {
+                return invalid(format!("{}.stale_after_ms", key), "must be positive".to_string())
+            }
+            spec.stale_after = Duration::from_millis(stale_after_ms);
+        }
+        spec.log = raw.log.clone();
+        if let Some(ref rotation) = raw.log_rotation {
+            spec.log_rotation = Rotation::of_str(rotation)
+                .or_else(|_| invalid(format!("{}.log_rotation", key), format!("invalid rotation {}, expected never, hourly, daily or a size such as 500MB", rotation)))?;
+        }
+        spec.backoff = backoff.clone();
+        Ok(spec)
+    }
+
+    fn sink_spec(key: &str, raw: &RawSink, feeds: &[FeedSpec]) -> Result<SinkSpec, Error> {
+        match *raw {
+            RawSink::Events => Ok(SinkSpec::Events),
+            RawSink::Arbitrage { ref products, ref taker_fees, depth } => {
+                if products.len() < 2 {
+                    return invalid(format!("{}.products", key), "at least two venues are required".to_string())
+                }
+                let mut venues = Vec::new();
+                for (name, product) in products.iter() {
+                    let key = format!("{}.products.{}", key, name);
+                    let venue = feed(key.clone(), name)?;
+                    let subscribed = feeds.iter()
+                        .any(|spec| spec.feed == venue && spec.products.contains(product));
+                    if !subscribed {
+                        return invalid(key, format!("{} is not subscribed to on {}", product, name))
+                    }
+                    venues.push((venue, FeedSpec::event_product_id(venue, product)));
+                }
+                let mut fees = Vec::new();
+                for (name, &fee) in taker_fees.iter() {
+                    let key = format!("{}.taker_fees.{}", key, name);
+                    let venue = feed(key.clone(), name)?;
+                    if !products.contains_key(name) {
+                        return invalid(key, format!("{} is not one of the venues", name))
+                    }
+                    if !(0.0..1.0).contains(&fee) {
+                        return invalid(key, format!("fee {} is not a fraction", fee))
+                    }
+                    fees.push((venue, fee));
+                }
+                Ok(SinkSpec::Arbitrage { products: venues, taker_fees: fees, depth: depth.unwrap_or(10) })
+            },
+        }
+    }
+
+    /// Builds the sinks, they are meant to be subscribed to the bus the feeds publish on.
+    /// The clock is the one the staleness of the books is evaluated at.
+    pub fn sinks(&self, clock: Arc<dyn Clock>) -> Vec<Box<dyn EventSink>> {
+        self.sinks.iter()
+            .map(|sink| -> Box<dyn EventSink> {
+                match *sink {
+                    SinkSpec::Events => Box::new(|event: &FeedEvent| debug!("{:?}", event)),
+                    SinkSpec::Arbitrage { ref products, ref taker_fees, depth } => {
+                        let venues: Vec<_> = products.iter()
+                            .map(|&(feed, ref product)| (feed, product.as_str()))
+                            .collect();
+                        let mut detector = ArbitrageDetector::new(&venues)
+                            .with_depth(depth)
+                            .with_clock(clock.clone());
+                        for &(feed, fee) in taker_fees {
+                            detector = detector.with_taker_fee(feed, fee);
+                        }
+                        for spec in self.feeds.iter() {
+                            detector.book().set_stale_after(spec.feed, spec.stale_after);
+                        }
+                        detector.subscribe(Box::new(|opportunity: &Opportunity| {
+                            info!("{} -> {}: {:.2} over {}ms", opportunity.buy_venue, opportunity.sell_venue,
+                                opportunity.expected_profit, opportunity.duration().num_milliseconds())
+                        }));
+                        Box::new(detector)
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::SystemClock;
+
+    #[test]
+    fn parse_test() {
+        let config = Config::parse(r#"
+            [[feeds]]
+            feed = "gdax"
+            products = ["BTC-USD", "ETH-USD"]
+            endpoint = "ws://127.0.0.1:8080"
+            stale_after_ms = 1000
+            log = "gdax.log"
+            log_rotation = "100MB"
+
+            [[feeds]]
+            feed = "gemini"
+            endpoint = "ws://127.0.0.1:8081"
+
+            [backoff]
+            initial_ms = 100
+            multiplier = 1.5
+
+            [[products]]
+            product = "ETH-BTC"
+            tick_size = "0.000001"
+            lot_size = "0.001"
+            strict = true
+
+            [[sinks]]
+            type = "events"
+
+            [[sinks]]
+            type = "arbitrage"
+            products = { gdax = "BTC-USD", gemini = "btcusd" }
+            taker_fees = { gdax = 0.0025 }
+        "#).unwrap();
+        let mut gdax = FeedSpec::new(Feed::Gdax, vec!["BTC-USD".to_string(), "ETH-USD".to_string()]);
+        gdax.server_name = Some("ws://127.0.0.1:8080".to_string());
+        gdax.stale_after = Duration::from_millis(1000);
+        gdax.log = Some("gdax.log".to_string());
+        gdax.log_rotation = Rotation::Size(100 << 20);
+        let mut gemini = FeedSpec::new(Feed::Gemini, vec!["btcusd".to_string()]);
+        gemini.server_name = Some("ws://127.0.0.1:8081".to_string());
+        let backoff = Backoff { initial: Duration::from_millis(100), multiplier: 1.5, ..Backoff::default() };
+        gdax.backoff = backoff.clone();
+        gemini.backoff = backoff;
+        assert_eq!(config.feeds, vec![gdax, gemini]);
+        let eth_btc = config.products.spec("ETH-BTC");
+        assert_eq!((eth_btc.price_precision, eth_btc.size_precision, eth_btc.strict), (6, 3, true));
+        assert_eq!(config.products.spec("BTC-USD"), Registry::default().spec("BTC-USD"));
+        assert_eq!(config.sinks[1], SinkSpec::Arbitrage {
+            products: vec![(Feed::Gdax, "BTC-USD".to_string()), (Feed::Gemini, "BTCUSD".to_string())],
+            taker_fees: vec![(Feed::Gdax, 0.0025)],
+            depth: 10,
+        });
+        assert_eq!(config.sinks(Arc::new(SystemClock)).len(), 2);
+    }
+
+    fn error_key(content: &str) -> String {
+        match Config::parse(content) {
+            Err(Error::Config { key, .. }) => key,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn validation_test() {
+        assert_eq!(error_key(""), "feeds");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[[feeds]]\nfeed = \"kraken\""), "feeds[1].feed");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\nproducts = [\"BTC-USD\", \"\"]"), "feeds[0].products[1]");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\nendpoint = \"localhost\""), "feeds[0].endpoint");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\nlog_rotation = \"weekly\""), "feeds[0].log_rotation");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\nstale_after_ms = \"1s\""), "feeds[0].stale_after_ms");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[[feeds]]\nfeed = \"gemini\"\nprodcts = []"), "feeds[1].prodcts");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[[products]]\nproduct = \"BTC-USD\"\ntick_size = 0.01\nlot_size = \"1\""), "products[0].tick_size");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\nproducts = [\"BTC-USD\""), "line 3 column 22");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gemini\"\nproducts = [\"btcusd\", \"ethusd\"]\nendpoint = \"ws://127.0.0.1:8080\""), "feeds[0].endpoint");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[backoff]\ninitial_ms = 0"), "backoff.initial_ms");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[backoff]\nmax_ms = 100"), "backoff.max_ms");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[backoff]\nmultiplier = 0.5"), "backoff.multiplier");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[backoff]\njitter = 1.5"), "backoff.jitter");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[[products]]\nproduct = \"BTC-USD\"\ntick_size = \"0\"\nlot_size = \"1\""), "products[0]");
+        assert_eq!(error_key("[[feeds]]\nfeed = \"gdax\"\n[[products]]\nproduct = \"BTC-USD\"\ntick_size = \"0.01\"\nlot_size = \"-1\""), "products[0]");
+        let sink = "[[feeds]]\nfeed = \"gdax\"\n[[sinks]]\ntype = \"arbitrage\"\nproducts = { gdax = \"BTC-USD\", gemini = \"btcusd\" }\n";
+        assert_eq!(error_key(sink), "sinks[0].products.gemini");
+    }
+}
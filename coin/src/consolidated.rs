@@ -0,0 +1,254 @@
+use std::cell::{Ref, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use book_processor::{BookProcessor, NotLiveStatus};
+use error::Error;
+use event::{Event, EventSink, Feed, FeedEvent};
+use price::Price;
+use quantity::Quantity;
+use side::Side;
+use time::Time;
+
+/// A price level across venues, with the size available on each of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Level {
+    /// The price of the level.
+    pub price: Price,
+    /// The total size over the venues.
+    pub size: Quantity,
+    /// The size on each venue, in the order of the venues.
+    pub venues: Vec<(Feed, Quantity)>,
+}
+
+/// Merges the n best levels of one side of several books by price, best price
+/// first. Side::Buy designates the bids.
+pub fn merge_levels(books: &[(Feed, &BookProcessor)], side: Side, n: usize) -> Result<Vec<Level>, Error> {
+    let mut levels: BTreeMap<Price, Level> = BTreeMap::new();
+    for &(feed, book) in books {
+        for (price, size) in book.top_levels(side, n) {
+            let level = levels.entry(price)
+                .or_insert_with(|| Level { price, size: Quantity::zero(), venues: Vec::new() });
+            level.size.accumulate(size)?;
+            level.venues.push((feed, size));
+        }
+    }
+    Ok(match side {
+        Side::Buy => levels.into_values().rev().take(n).collect(),
+        Side::Sell => levels.into_values().take(n).collect(),
+    })
+}
+
+// The copy of a venue book rebuilt from its events.
+struct Venue {
+    product_id: String,
+    book: BookProcessor,
+    in_snapshot: bool,
+    // The last status reported by the venue processor.
+    status: Result<(), NotLiveStatus>,
+}
+
+impl Venue {
+    // A venue is left out of the consolidation until both its processor and the
+    // copy of its book report it as live, e.g. while in snapshot, after a sequence
+    // gap or when stale.
+    fn is_live(&self, time: &Time) -> bool {
+        self.book.status(time).is_ok() && self.status.is_ok()
+    }
+}
+
+/// The book of a single instrument consolidated over several venues, e.g. BTC-USD
+/// on gdax and gemini. It is maintained from the events published by the venue
+/// processors so that it can be fed from a live bus or from a replay.
+pub struct ConsolidatedBook {
+    venues: RefCell<BTreeMap<Feed, Venue>>,
+}
+
+impl ConsolidatedBook {
+    /// Takes the product id of the instrument on each venue.
+    pub fn new(products: &[(Feed, &str)]) -> ConsolidatedBook {
+        let venues = products.iter()
+            .map(|&(feed, product_id)| {
+                let venue = Venue {
+                    product_id: product_id.to_string(),
+                    book: BookProcessor::new(),
+                    in_snapshot: false,
+                    status: Err(NotLiveStatus::InitialSnapshot),
+                };
+                (feed, venue)
+            })
+            .collect();
+        ConsolidatedBook { venues: RefCell::new(venues) }
+    }
+
+    /// How long the book of a venue can go without updates before dropping out.
+    pub fn set_stale_after(&self, feed: Feed, stale_after: Duration) {
+        if let Some(venue) = self.venues.borrow_mut().get_mut(&feed) {
+            venue.book.set_stale_after(stale_after);
+        }
+    }
+
+    /// The book of a single venue, None if the venue is not part of the consolidation.
+    pub fn venue_book(&self, feed: Feed) -> Option<Ref<'_, BookProcessor>> {
+        let venues = self.venues.borrow();
+        if !venues.contains_key(&feed) {
+            return None
+        }
+        Some(Ref::map(venues, |venues| &venues[&feed].book))
+    }
+
+    /// Whether a venue is in the middle of a snapshot, the consolidated book is
+    /// then only partially rebuilt.
+    pub fn in_snapshot(&self) -> bool {
+        self.venues.borrow().values().any(|venue| venue.in_snapshot)
+    }
+
+    /// The venues that currently take part in the consolidation.
+    pub fn live_venues(&self, time: &Time) -> Vec<Feed> {
+        self.venues.borrow().iter()
+            .filter(|(_, venue)| venue.is_live(time))
+            .map(|(&feed, _)| feed)
+            .collect()
+    }
+
+    /// The n best consolidated levels of one side, best price first.
+    pub fn top_levels(&self, side: Side, n: usize, time: &Time) -> Result<Vec<Level>, Error> {
+        let venues = self.venues.borrow();
+        let books: Vec<_> = venues.iter()
+            .filter(|(_, venue)| venue.is_live(time))
+            .map(|(&feed, venue)| (feed, &venue.book))
+            .collect();
+        merge_levels(&books, side, n)
+    }
+
+    /// The highest consolidated bid.
+    pub fn best_bid(&self, time: &Time) -> Result<Option<Level>, Error> {
+        Ok(self.top_levels(Side::Buy, 1, time)?.pop())
+    }
+
+    /// The lowest consolidated ask.
+    pub fn best_ask(&self, time: &Time) -> Result<Option<Level>, Error> {
+        Ok(self.top_levels(Side::Sell, 1, time)?.pop())
+    }
+}
+
+impl EventSink for ConsolidatedBook {
+    fn on_event(&self, event: &FeedEvent) {
+        let mut venues = self.venues.borrow_mut();
+        let venue = match venues.get_mut(&event.feed) {
+            Some(venue) => venue,
+            None => return,
+        };
+        match event.event {
+            Event::SnapshotStart { ref product_id } if *product_id == venue.product_id => {
+                venue.book.clear_on_snapshot();
+                venue.in_snapshot = true;
+            },
+            Event::SnapshotEnd { ref product_id } if *product_id == venue.product_id => {
+                venue.in_snapshot = false;
+            },
+            Event::LevelUpdate { ref product_id, side, price, size } if *product_id == venue.product_id => {
+                // The copy missed the update, it is left out until the next snapshot.
+                if let Err(error) = venue.book.on_update(&event.time, side, price, size, venue.in_snapshot) {
+                    error!("{} {}: {}", event.feed, product_id, error);
+                    venue.book.on_sequence_gap();
+                }
+            },
+            Event::StatusChange { ref product_id, ref status } if *product_id == venue.product_id => {
+                if *status == Err(NotLiveStatus::SequenceGap) {
+                    venue.book.on_sequence_gap();
+                }
+                venue.status = status.clone();
+            },
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn price(str: &str) -> Price {
+        Price::parse_str(str).unwrap()
+    }
+
+    fn qty(str: &str) -> Quantity {
+        Quantity::parse_str(str).unwrap()
+    }
+
+    fn publish(book: &ConsolidatedBook, feed: Feed, time: &Time, events: Vec<Event>) {
+        for event in events {
+            book.on_event(&FeedEvent { feed, time: time.clone(), event });
+        }
+    }
+
+    fn snapshot(product_id: &str, levels: &[(Side, &str, &str)]) -> Vec<Event> {
+        let mut events = vec![Event::SnapshotStart { product_id: product_id.to_string() }];
+        for &(side, price_, size) in levels {
+            events.push(Event::LevelUpdate { product_id: product_id.to_string(), side, price: price(price_), size: qty(size) });
+        }
+        events.push(Event::SnapshotEnd { product_id: product_id.to_string() });
+        events
+    }
+
+    #[test]
+    fn consolidation_test() {
+        let book = ConsolidatedBook::new(&[(Feed::Gdax, "BTC-USD"), (Feed::Gemini, "BTCUSD")]);
+        let start = Time::parse("2018-01-17 21:00:00.000000000").unwrap();
+        publish(&book, Feed::Gdax, &start, snapshot("BTC-USD", &[(Side::Buy, "100", "1"), (Side::Buy, "99", "2"), (Side::Sell, "101", "1")]));
+        publish(&book, Feed::Gemini, &start, snapshot("BTCUSD", &[(Side::Buy, "100", "0.5"), (Side::Sell, "100.5", "3")]));
+        // Events of other products are ignored.
+        publish(&book, Feed::Gdax, &start, snapshot("ETH-USD", &[(Side::Buy, "200", "1")]));
+
+        let now = Time::parse("2018-01-17 21:00:00.100000000").unwrap();
+        let update = |feed, product_id: &str, side, price_: &str, size: &str| {
+            publish(&book, feed, &now, vec![Event::LevelUpdate { product_id: product_id.to_string(), side, price: price(price_), size: qty(size) }]);
+        };
+        let live = |feed, product_id: &str| {
+            publish(&book, feed, &now, vec![Event::StatusChange { product_id: product_id.to_string(), status: Ok(()) }]);
+        };
+        // The venues are left out until the processors report their books as live.
+        assert_eq!(book.live_venues(&now), vec![]);
+        update(Feed::Gdax, "BTC-USD", Side::Sell, "102", "1");
+        live(Feed::Gdax, "BTC-USD");
+        assert_eq!(book.live_venues(&now), vec![Feed::Gdax]);
+        update(Feed::Gemini, "BTCUSD", Side::Sell, "103", "1");
+        live(Feed::Gemini, "BTCUSD");
+        assert_eq!(book.live_venues(&now), vec![Feed::Gdax, Feed::Gemini]);
+        assert_eq!(book.best_bid(&now), Ok(Some(Level {
+            price: price("100"),
+            size: qty("1.5"),
+            venues: vec![(Feed::Gdax, qty("1")), (Feed::Gemini, qty("0.5"))],
+        })));
+        assert_eq!(book.best_ask(&now), Ok(Some(Level {
+            price: price("100.5"),
+            size: qty("3"),
+            venues: vec![(Feed::Gemini, qty("3"))],
+        })));
+        let prices: Vec<_> = book.top_levels(Side::Sell, 3, &now).unwrap().iter().map(|level| level.price).collect();
+        assert_eq!(prices, vec![price("100.5"), price("101"), price("102")]);
+
+        // Gemini goes stale and drops out.
+        let later = Time::parse("2018-01-17 21:00:00.700000000").unwrap();
+        publish(&book, Feed::Gdax, &later, vec![Event::LevelUpdate { product_id: "BTC-USD".to_string(), side: Side::Buy, price: price("99"), size: qty("3") }]);
+        assert_eq!(book.live_venues(&later), vec![Feed::Gdax]);
+        assert_eq!(book.best_ask(&later).unwrap().map(|level| level.price), Some(price("101")));
+        assert_eq!(book.venue_book(Feed::Gemini).unwrap().best_ask(), Some((price("100.5"), qty("3"))));
+
+        // So does a venue with a sequence gap, until it gets a new snapshot.
+        let status = |status| vec![Event::StatusChange { product_id: "BTCUSD".to_string(), status }];
+        publish(&book, Feed::Gemini, &later, snapshot("BTCUSD", &[(Side::Buy, "100", "0.5"), (Side::Sell, "100.5", "3")]));
+        publish(&book, Feed::Gemini, &later, status(Ok(())));
+        assert_eq!(book.live_venues(&later), vec![Feed::Gdax]);
+        publish(&book, Feed::Gemini, &later, vec![Event::LevelUpdate { product_id: "BTCUSD".to_string(), side: Side::Sell, price: price("103"), size: qty("0") }]);
+        assert_eq!(book.live_venues(&later), vec![Feed::Gdax, Feed::Gemini]);
+        publish(&book, Feed::Gemini, &later, status(Err(NotLiveStatus::SequenceGap)));
+        assert_eq!(book.live_venues(&later), vec![Feed::Gdax]);
+
+        // A snapshot error reported by gdax leaves no venue.
+        publish(&book, Feed::Gdax, &later, vec![Event::StatusChange { product_id: "BTC-USD".to_string(), status: Err(NotLiveStatus::SnapshotError) }]);
+        assert_eq!(book.live_venues(&later), vec![]);
+        assert_eq!(book.best_bid(&later), Ok(None));
+    }
+}
@@ -0,0 +1,119 @@
+// Helpers for the fixed-point decimal types, a value v with scale s represents v / 10^s.
+use std;
+
+/// Parses a decimal string such as -12.345 into an integer with `scale` digits
+/// after the dot. Digits beyond `max_digits` are only accepted if they are zeros.
+/// Assumes ascii encoding.
+pub fn parse(str: &str, scale: u32, max_digits: u32) -> Result<i64, String> {
+    let max_digits = max_digits.min(scale);
+    let (negative, digits) =
+        if let Some(digits) = str.strip_prefix('-') {
+            (true, digits)
+        } else {
+            (false, str)
+        };
+    if digits.is_empty() || digits == "." {
+        Err(format!("unable to parse as decimal {}", str))?
+    }
+    let overflow = || format!("unable to parse as decimal (overflow) {}", str);
+    let mut value: i64 = 0;
+    let mut seen_dot = false;
+    let mut post_dot_cnt = 0;
+    for c in digits.chars() {
+        if c == '.' && !seen_dot {
+            seen_dot = true;
+            continue
+        }
+        let digit = match c.to_digit(10) {
+            Some(digit) => digit as i64,
+            None => Err(format!("unable to parse as decimal {}", str))?
+        };
+        if seen_dot {
+            if post_dot_cnt < max_digits {
+                post_dot_cnt += 1;
+            } else {
+                if digit != 0 {
+                    Err(format!("unable to parse as decimal (too many digits) {}", str))?
+                }
+                continue
+            }
+        }
+        value = value.checked_mul(10)
+            .and_then(|value| value.checked_add(digit))
+            .ok_or_else(overflow)?;
+    }
+    for _ in post_dot_cnt..scale {
+        value = value.checked_mul(10).ok_or_else(overflow)?;
+    }
+    Ok(if negative { -value } else { value })
+}
+
+/// Returns the number of significant digits after the dot of a decimal string.
+pub fn decimals(str: &str) -> u32 {
+    match str.find('.') {
+        None => 0,
+        Some(dot) => str[dot + 1..].trim_end_matches('0').len() as u32,
+    }
+}
+
+/// Writes the exact decimal representation of value, without trailing zeros.
+pub fn fmt(value: i64, scale: u32, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let unit = 10u64.pow(scale);
+    let abs = value.unsigned_abs();
+    let sign = if value < 0 { "-" } else { "" };
+    let (pre_dot, post_dot) = (abs / unit, abs % unit);
+    let repr =
+        if post_dot == 0 {
+            format!("{}{}", sign, pre_dot)
+        } else {
+            let post_dot = format!("{:0width$}", post_dot, width = scale as usize);
+            format!("{}{}.{}", sign, pre_dot, post_dot.trim_end_matches('0'))
+        };
+    f.pad(&repr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Decimal(i64, u32);
+
+    impl std::fmt::Display for Decimal {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            fmt(self.0, self.1, f)
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("0", 3, 3), Ok(0));
+        assert_eq!(parse("1.5", 3, 3), Ok(1_500));
+        assert_eq!(parse("-1.5", 3, 3), Ok(-1_500));
+        assert_eq!(parse(".5", 3, 3), Ok(500));
+        assert_eq!(parse("2.", 3, 3), Ok(2_000));
+        assert_eq!(parse("1.2340000", 3, 3), Ok(1_234));
+        assert!(parse("1.25", 3, 1).is_err());
+        assert_eq!(parse("1.20", 3, 1), Ok(1_200));
+        assert!(parse("1.2345", 3, 3).is_err());
+        assert!(parse("", 3, 3).is_err());
+        assert!(parse("-", 3, 3).is_err());
+        assert!(parse(".", 3, 3).is_err());
+        assert!(parse("1.2.3", 3, 3).is_err());
+        assert!(parse("1e3", 3, 3).is_err());
+        assert!(parse("+1", 3, 3).is_err());
+        assert!(parse("99999999999999999999", 3, 3).is_err());
+        assert_eq!(decimals("0.00100"), 3);
+        assert_eq!(decimals("100"), 0);
+    }
+
+    #[test]
+    fn fmt_test() {
+        assert_eq!(Decimal(0, 3).to_string(), "0");
+        assert_eq!(Decimal(1_500, 3).to_string(), "1.5");
+        assert_eq!(Decimal(-1_500, 3).to_string(), "-1.5");
+        assert_eq!(Decimal(-5, 3).to_string(), "-0.005");
+        assert_eq!(Decimal(42_000, 3).to_string(), "42");
+        assert_eq!(Decimal(i64::MIN, 0).to_string(), "-9223372036854775808");
+        assert_eq!(format!("{:>6}", Decimal(1_500, 3)), "   1.5");
+    }
+}
@@ -0,0 +1,168 @@
+use std;
+use std::io;
+
+use serde_json;
+use ws;
+
+/// The errors of the feeds, the transports and the configuration.
+#[derive(Debug)]
+pub enum Error {
+    /// A value or a message that cannot be decoded.
+    Parse(String),
+    /// A well formed message that the feed is not expected to send, e.g. an
+    /// unknown message type or product.
+    Protocol(String),
+    /// Some messages were missed.
+    SequenceGap {
+        /// The first missing sequence number.
+        expected: i64,
+        /// The sequence number of the message received instead.
+        received: i64,
+    },
+    /// A sequence number that goes backwards.
+    OutOfOrder {
+        /// The last sequence number processed.
+        last: i64,
+        /// The sequence number of the late message.
+        received: i64,
+    },
+    /// A file or socket error.
+    Io(io::Error),
+    /// The connection to the server failed.
+    Transport(String),
+    /// An invalid configuration.
+    Config {
+        /// The path to the offending entry, e.g. `feeds[1].products`, or its
+        /// position when the file cannot be parsed.
+        key: String,
+        /// What is wrong with the entry.
+        message: String,
+    },
+    /// An error raised when processing a message, together with the message.
+    Message {
+        /// The message as received.
+        message: String,
+        /// The error raised.
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Attaches the offending message, this is a no-op if one is already attached.
+    pub fn in_message(self, message: &str) -> Error {
+        match self {
+            Error::Message { .. } => self,
+            error => Error::Message { message: message.to_string(), error: Box::new(error) },
+        }
+    }
+
+    /// The error without the message context.
+    pub fn root(&self) -> &Error {
+        match *self {
+            Error::Message { ref error, .. } => error.root(),
+            ref error => error,
+        }
+    }
+
+    /// The message that caused the error, if known.
+    pub fn message(&self) -> Option<&str> {
+        match *self {
+            Error::Message { ref message, .. } => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Whether some messages were missed, the processors recover from these by themselves.
+    pub fn is_sequence_error(&self) -> bool {
+        matches!(*self.root(), Error::SequenceGap { .. } | Error::OutOfOrder { .. })
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Error::Parse(ref error) => write!(f, "parse error: {}", error),
+            Error::Protocol(ref error) => write!(f, "protocol error: {}", error),
+            Error::SequenceGap { expected, received } => {
+                write!(f, "sequence gap, expected {} got {}", expected, received)
+            },
+            Error::OutOfOrder { last, received } => {
+                write!(f, "out of order sequence number {} after {}", received, last)
+            },
+            Error::Io(ref error) => write!(f, "i/o error: {}", error),
+            Error::Transport(ref error) => write!(f, "transport error: {}", error),
+            Error::Config { ref key, ref message } => write!(f, "invalid configuration at {}: {}", key, message),
+            Error::Message { ref message, ref error } => write!(f, "{} in message {}", error, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref error) => Some(error),
+            Error::Message { ref error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
+}
+
+// I/O errors are compared by kind so that results can be compared in tests.
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        match (self, other) {
+            (Error::Parse(a), Error::Parse(b)) => a == b,
+            (Error::Protocol(a), Error::Protocol(b)) => a == b,
+            (Error::SequenceGap { expected, received }, Error::SequenceGap { expected: e, received: r }) => {
+                expected == e && received == r
+            },
+            (Error::OutOfOrder { last, received }, Error::OutOfOrder { last: l, received: r }) => {
+                last == l && received == r
+            },
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+            (Error::Transport(a), Error::Transport(b)) => a == b,
+            (Error::Config { key, message }, Error::Config { key: k, message: m }) => {
+                key == k && message == m
+            },
+            (Error::Message { message, error }, Error::Message { message: m, error: e }) => {
+                message == m && error == e
+            },
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::Parse(error.to_string())
+    }
+}
+
+impl From<ws::Error> for Error {
+    fn from(error: ws::Error) -> Error {
+        Error::Transport(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn context_test() {
+        let error = Error::SequenceGap { expected: 3, received: 5 }.in_message("{}");
+        assert_eq!(error.message(), Some("{}"));
+        assert_eq!(*error.root(), Error::SequenceGap { expected: 3, received: 5 });
+        assert!(error.is_sequence_error());
+        assert_eq!(error.to_string(), "sequence gap, expected 3 got 5 in message {}");
+        let error = error.in_message("other");
+        assert_eq!(error.message(), Some("{}"));
+        assert!(!Error::Parse("1,5".to_string()).is_sequence_error());
+    }
+}
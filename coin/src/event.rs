@@ -0,0 +1,248 @@
+use std;
+use std::cell::RefCell;
+
+use book_processor::NotLiveStatus;
+use error::Error;
+use price::Price;
+use quantity::Quantity;
+use side::Side;
+use time::Time;
+use trade::Trade;
+
+/// The exchange feeds that produce events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feed {
+    /// The GDAX (Coinbase) level2 feed.
+    Gdax,
+    /// The Gemini market data feed, a connection per symbol.
+    Gemini,
+}
+
+impl Feed {
+    /// The name of the feed on the command line and in the configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feed::Gdax => "gdax",
+            Feed::Gemini => "gemini",
+        }
+    }
+
+    /// The identifier of the feed in binary captures, 0 is left for unknown feeds.
+    pub fn id(self) -> u8 {
+        match self {
+            Feed::Gdax => 1,
+            Feed::Gemini => 2,
+        }
+    }
+
+    /// The feed of a binary capture identifier.
+    pub fn of_id(id: u8) -> Result<Feed, Error> {
+        match id {
+            1 => Ok(Feed::Gdax),
+            2 => Ok(Feed::Gemini),
+            _ => Err(Error::Parse(format!("unsupported feed id {}", id))),
+        }
+    }
+
+    /// The feed of a name such as gdax.
+    pub fn of_str(str: &str) -> Result<Feed, Error> {
+        match str {
+            "gdax" => Ok(Feed::Gdax),
+            "gemini" => Ok(Feed::Gemini),
+            _ => Err(Error::Parse(format!("unsupported feed {}", str))),
+        }
+    }
+}
+
+impl std::fmt::Display for Feed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// An auction, as run by gemini. The price and quantity are missing when the
+/// auction fails or would fail.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Auction {
+    /// The product the auction is for.
+    pub product_id: String,
+    /// The identifier of the auction on the exchange.
+    pub auction_id: i64,
+    /// Whether the auction resulted, or would result, in a trade.
+    pub succeeded: bool,
+    /// The auction price.
+    pub price: Option<Price>,
+    /// The quantity traded at the auction price.
+    pub quantity: Option<Quantity>,
+}
+
+/// The market data events emitted by the feeds, normalized across exchanges.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The levels updates until SnapshotEnd make a full snapshot of the book,
+    /// the previous levels should be discarded.
+    SnapshotStart {
+        /// The product the snapshot is for.
+        product_id: String,
+    },
+    /// The snapshot is complete.
+    SnapshotEnd {
+        /// The product the snapshot is for.
+        product_id: String,
+    },
+    /// The new total size at a price level, a zero size removes the level.
+    LevelUpdate {
+        /// The product of the book.
+        product_id: String,
+        /// Buy for the bids, Sell for the asks.
+        side: Side,
+        /// The price of the level.
+        price: Price,
+        /// The total size at the level.
+        size: Quantity,
+    },
+    /// A trade, counted once.
+    Trade(Trade),
+    /// An auction has been scheduled, the indicative prices follow until the result.
+    AuctionOpen {
+        /// The product of the auction.
+        product_id: String,
+        /// When the auction is due to run.
+        auction_time: Time,
+    },
+    /// The price the auction would run at now.
+    AuctionIndicative(Auction),
+    /// The outcome of the auction.
+    AuctionResult(Auction),
+    /// The exchange is alive, the book status is checked.
+    Heartbeat {
+        /// The product of the heartbeat.
+        product_id: String,
+    },
+    /// An error reported by the exchange.
+    ExchangeError {
+        /// The error message as sent by the exchange.
+        message: String,
+    },
+    /// The book status has changed, Ok means that the book is live.
+    StatusChange {
+        /// The product of the book.
+        product_id: String,
+        /// The new status.
+        status: Result<(), NotLiveStatus>,
+    },
+}
+
+/// An event together with the feed it comes from and the time it was received at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedEvent {
+    /// The feed the event comes from.
+    pub feed: Feed,
+    /// The receive time of the message the event comes from.
+    pub time: Time,
+    /// The event itself.
+    pub event: Event,
+}
+
+/// A consumer of the events published on an EventBus.
+pub trait EventSink {
+    /// Called for each event, in publication order.
+    fn on_event(&self, event: &FeedEvent);
+
+    /// Called once no more events will be published, e.g. at the end of a replay,
+    /// with the time of the last event.
+    fn on_end(&self, _time: &Time) {}
+}
+
+impl<F: Fn(&FeedEvent)> EventSink for F {
+    fn on_event(&self, event: &FeedEvent) {
+        self(event)
+    }
+}
+
+/// Dispatches the events to all the subscribed sinks, in subscription order.
+pub struct EventBus {
+    sinks: RefCell<Vec<Box<dyn EventSink>>>,
+    last_time: RefCell<Option<Time>>,
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+impl EventBus {
+    /// A bus without any sink.
+    pub fn new() -> EventBus {
+        EventBus {
+            sinks: RefCell::new(Vec::new()),
+            last_time: RefCell::new(None),
+        }
+    }
+
+    /// Sinks cannot subscribe from within on_event.
+    pub fn subscribe(&self, sink: Box<dyn EventSink>) {
+        self.sinks.borrow_mut().push(sink);
+    }
+
+    /// Sends an event to all the sinks.
+    pub fn publish(&self, event: &FeedEvent) {
+        *self.last_time.borrow_mut() = Some(event.time.clone());
+        for sink in self.sinks.borrow().iter() {
+            sink.on_event(event);
+        }
+    }
+
+    /// Publishes the events a processor collected while processing a message,
+    /// once its state has been released so that the sinks can query it. When
+    /// the message failed to process, only the status changes are published as
+    /// the other events would describe a partial update, e.g. a snapshot without
+    /// its end. A message that reveals a sequence gap has been fully processed.
+    pub fn publish_processed(&self, feed: Feed, time: &Time, mut events: Vec<Event>, result: &Result<(), Error>) {
+        if result.as_ref().is_err_and(|error| !error.is_sequence_error()) {
+            events.retain(|event| matches!(*event, Event::StatusChange { .. }));
+        }
+        for event in events {
+            self.publish(&FeedEvent { feed, time: time.clone(), event });
+        }
+    }
+
+    /// Tells the sinks that the stream of events is over, nothing is sent if no
+    /// event has been published.
+    pub fn end(&self) {
+        if let Some(ref time) = *self.last_time.borrow() {
+            for sink in self.sinks.borrow().iter() {
+                sink.on_end(time);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn bus_test() {
+        let bus = EventBus::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        for index in 0..2 {
+            let received = received.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| {
+                received.borrow_mut().push((index, event.clone()))
+            }));
+        }
+        let event = FeedEvent {
+            feed: Feed::Gdax,
+            time: Time::epoch(),
+            event: Event::Heartbeat { product_id: "BTC-USD".to_string() },
+        };
+        bus.publish(&event);
+        assert_eq!(*received.borrow(), vec![(0, event.clone()), (1, event)]);
+        assert_eq!(Feed::of_str("gemini"), Ok(Feed::Gemini));
+        assert_eq!(Feed::Gemini.to_string(), "gemini");
+        assert!(Feed::of_str("kraken").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use book_processor::DEFAULT_STALE_AFTER;
+use capture;
+use capture::{Header, Rotation};
+use clock::Clock;
+use error::Error;
+use event::{EventBus, Feed, FeedEvent};
+use gdax;
+use gemini;
+use message_processor::MessageProcessor;
+use product::Registry;
+use shutdown::Shutdown;
+use supervisor::Backoff;
+use transport::{Endpoint, Transport};
+use websocket::WebSocket;
+
+/// A feed together with the products to subscribe to, written as
+/// gdax:BTC-USD,ETH-USD on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedSpec {
+    /// The exchange to connect to.
+    pub feed: Feed,
+    /// The products as named by the exchange, e.g. BTC-USD or btcusd.
+    pub products: Vec<String>,
+    /// Replaces the exchange server for all the connections, e.g. to use a local stub.
+    pub server_name: Option<String>,
+    /// How long the books can go without updates before being stale.
+    pub stale_after: Duration,
+    /// Where to capture the raw messages, either a filename or stdout.
+    pub log: Option<String>,
+    /// When to start a new capture file.
+    pub log_rotation: Rotation,
+    /// The delays between the reconnections of each connection.
+    pub backoff: Backoff,
+}
+
+impl FeedSpec {
+    /// The products are optional and default to bitcoin.
+    pub fn parse(str: &str) -> Result<FeedSpec, Error> {
+        let mut parts = str.splitn(2, ':');
+        let feed = Feed::of_str(parts.next().unwrap_or(""))?;
+        let products = match parts.next() {
+            Some(products) => products.split(',').map(|product| product.to_string()).collect(),
+            None => FeedSpec::default_products(feed),
+        };
+        if products.iter().any(|product| product.is_empty()) {
+            return Err(Error::Parse(format!("empty product in feed {}", str)))
+        }
+        Ok(FeedSpec::new(feed, products))
+    }
+
+    /// A spec connecting to the exchange, without capture.
+    pub fn new(feed: Feed, products: Vec<String>) -> FeedSpec {
+        FeedSpec {
+            feed,
+            products,
+            server_name: None,
+            stale_after: DEFAULT_STALE_AFTER,
+            log: None,
+            log_rotation: Rotation::Never,
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// The bitcoin product of the feed.
+    pub fn default_products(feed: Feed) -> Vec<String> {
+        match feed {
+            Feed::Gdax => vec!["BTC-USD".to_string()],
+            Feed::Gemini => vec!["btcusd".to_string()],
+        }
+    }
+
+    /// Splits the spec by connection, a gemini connection serves a single symbol.
+    /// Each connection then logs to its own file, suffixed by the symbol.
+    pub fn connections(&self) -> Vec<FeedSpec> {
+        match self.feed {
+            Feed::Gdax => vec![self.clone()],
+            Feed::Gemini if self.products.len() == 1 => vec![self.clone()],
+            Feed::Gemini => {
+                self.products.iter()
+                    .map(|product| {
+                        let log = self.log.as_ref().map(|log| {
+                            if log == "stdout" { log.clone() } else { capture::with_suffix(log, product) }
+                        });
+                        FeedSpec { products: vec![product.clone()], log, ..self.clone() }
+                    })
+                    .collect()
+            },
+        }
+    }
+
+    /// The spec a capture was made with, e.g. to replay it.
+    pub fn of_header(header: &Header) -> FeedSpec {
+        FeedSpec::new(header.feed, header.products.clone())
+    }
+
+    /// The product ids used in the events, gemini uses upper case symbols.
+    pub fn event_product_id(feed: Feed, product: &str) -> String {
+        match feed {
+            Feed::Gdax => product.to_string(),
+            Feed::Gemini => product.to_uppercase(),
+        }
+    }
+
+    /// The endpoint of the processor, with the server overridden if required.
+    /// The override has to be a websocket url, as it is not checked until the
+    /// first connection otherwise and the connection is then retried forever.
+    pub fn endpoint(&self, processor: &dyn MessageProcessor) -> Result<Endpoint, Error> {
+        let endpoint = processor.endpoint();
+        match self.server_name {
+            Some(ref server_name) if !server_name.starts_with("ws://") && !server_name.starts_with("wss://") =>
+                Err(Error::Parse(format!("invalid endpoint {} for {}, expected a ws:// or wss:// url", server_name, self))),
+            Some(ref server_name) => Ok(Endpoint { server_name: server_name.clone(), ..endpoint }),
+            None => Ok(endpoint),
+        }
+    }
+
+    /// This returns a box as the MessageProcessor size is unknown at compile time.
+    /// The staleness of the books is evaluated at the time of the clock.
+    pub fn processor(&self, registry: &Registry, bus: Rc<EventBus>, clock: Arc<dyn Clock>) -> Result<Box<dyn MessageProcessor>, Error> {
+        match self.feed {
+            Feed::Gdax => {
+                let processor = gdax::JsonProcessor::new(self.products.clone(), registry, bus).with_clock(clock);
+                processor.set_stale_after(self.stale_after);
+                Ok(Box::new(processor))
+            },
+            Feed::Gemini => {
+                match self.products.as_slice() {
+                    [symbol] => {
+                        let processor = gemini::JsonProcessor::new(symbol, registry, bus).with_clock(clock);
+                        processor.set_stale_after(self.stale_after);
+                        Ok(Box::new(processor))
+                    },
+                    _ => Err(Error::Parse(format!("gemini serves a single symbol per connection, got {}", self))),
+                }
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for FeedSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.feed, self.products.join(","))
+    }
+}
+
+// What the feed threads send to the calling thread.
+enum Message {
+    Event(FeedEvent),
+    // The connection could not be started, e.g. because of an invalid endpoint.
+    Failed(String, Error),
+}
+
+// Builds the processor of a connection, forwarding its events to `sender`.
+fn start(spec: &FeedSpec, registry: &Registry, sender: mpsc::Sender<Message>, clock: Arc<dyn Clock>)
+        -> Result<(Box<dyn MessageProcessor>, Endpoint), Error> {
+    let bus = Rc::new(EventBus::new());
+    bus.subscribe(Box::new(move |event: &FeedEvent| {
+        // The receiver only goes away once all the feeds have stopped.
+        let _ = sender.send(Message::Event(event.clone()));
+    }));
+    let processor = spec.processor(registry, bus, clock)?;
+    let endpoint = spec.endpoint(&*processor)?;
+    let processor = match spec.log {
+        Some(ref log) => {
+            let logger = processor.rotating_logger(log, spec.log_rotation)?.with_endpoint(endpoint.clone());
+            Box::new(logger.forward_to(processor))
+        },
+        None => processor,
+    };
+    Ok((processor, endpoint))
+}
+
+/// Runs each feed connection on its own thread until the shutdown is requested.
+/// The processors are not shared between threads, their events are forwarded to
+/// the calling thread and published on `bus` from there. A connection that fails
+/// to start requests the shutdown of the others right away.
+pub fn run(specs: &[FeedSpec], registry: &Registry, bus: &EventBus, clock: Arc<dyn Clock>, shutdown: &Shutdown) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::channel::<Message>();
+    let mut threads = Vec::new();
+    for spec in specs.iter().flat_map(|spec| spec.connections()) {
+        let sender = sender.clone();
+        let registry = registry.clone();
+        let clock = clock.clone();
+        let shutdown = shutdown.clone();
+        let name = spec.to_string();
+        let thread = thread::Builder::new().name(name.clone()).spawn(move || {
+            let (processor, endpoint) = match start(&spec, &registry, sender.clone(), clock.clone()) {
+                Ok(started) => started,
+                Err(error) => {
+                    let _ = sender.send(Message::Failed(spec.to_string(), error));
+                    return Ok(())
+                },
+            };
+            WebSocket::new(endpoint)
+                .with_backoff(spec.backoff.clone())
+                .with_clock(clock)
+                .with_shutdown(shutdown)
+                .run(&*processor)
+        })?;
+        threads.push((name, thread));
+    }
+    drop(sender);
+    let mut result = Ok(());
+    for message in receiver {
+        match message {
+            Message::Event(event) => bus.publish(&event),
+            Message::Failed(name, error) => {
+                error!("feed {} failed to start: {}", name, error);
+                shutdown.request();
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            },
+        }
+    }
+    for (name, thread) in threads {
+        let error = match thread.join() {
+            Ok(Ok(())) => continue,
+            Ok(Err(error)) => error,
+            Err(_) => Error::Transport(format!("feed {} panicked", name)),
+        };
+        error!("feed {} stopped: {}", name, error);
+        if result.is_ok() {
+            result = Err(error);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::SystemClock;
+
+    #[test]
+    fn spec_test() {
+        let spec = FeedSpec::parse("gdax:BTC-USD,ETH-USD").unwrap();
+        assert_eq!(spec, FeedSpec::new(Feed::Gdax, vec!["BTC-USD".to_string(), "ETH-USD".to_string()]));
+        assert_eq!(spec.connections(), vec![spec.clone()]);
+        assert_eq!(spec.to_string(), "gdax:BTC-USD,ETH-USD");
+        let spec = FeedSpec::parse("gemini").unwrap();
+        assert_eq!(spec.products, vec!["btcusd".to_string()]);
+        let mut spec = FeedSpec::parse("gemini:btcusd,ethusd").unwrap();
+        spec.log = Some("gemini.log.gz".to_string());
+        let logs: Vec<_> = spec.connections().into_iter().map(|connection| connection.log.unwrap()).collect();
+        assert_eq!(logs, vec!["gemini-btcusd.log.gz".to_string(), "gemini-ethusd.log.gz".to_string()]);
+        assert!(spec.processor(&Registry::default(), Rc::new(EventBus::new()), Arc::new(SystemClock)).is_err());
+        assert!(FeedSpec::parse("kraken").is_err());
+        assert!(FeedSpec::parse("gdax:BTC-USD,").is_err());
+    }
+
+    #[test]
+    fn startup_error_test() {
+        // Nothing listens on the endpoint of the first feed, it keeps reconnecting
+        // until the second one fails to start.
+        let mut gdax = FeedSpec::parse("gdax").unwrap();
+        gdax.server_name = Some("ws://127.0.0.1:1".to_string());
+        let mut gemini = FeedSpec::parse("gemini").unwrap();
+        gemini.server_name = Some("api.gemini.com".to_string());
+        let shutdown = Shutdown::new();
+        let result = run(&[gdax, gemini], &Registry::default(), &EventBus::new(), Arc::new(SystemClock), &shutdown);
+        match result {
+            Err(Error::Parse(message)) => assert!(message.contains("api.gemini.com")),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(shutdown.is_requested());
+    }
+}
@@ -1,15 +1,35 @@
 use serde_json;
 
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
-use book_processor::BookProcessor;
-use message_processor::MessageProcessor;
-use side::Side;
+use book_processor::{BookProcessor, NotLiveStatus};
+use clock::Clock;
+use error::Error;
+use event::{Event, EventBus, Feed, FeedEvent};
+use message_processor::{Action, MessageProcessor};
 use price::Price;
+use side::Side;
+use product::{ProductSpec, Registry};
+use quantity::Quantity;
+use sequence::Sequence;
 use time::Time;
+use trade::{Trade, TradeProcessor};
+use transport::Endpoint;
+
+#[derive(Debug, Serialize)]
+struct Subscribe<'a> {
+    #[serde(rename = "type")]
+    type_: &'a str,
+    product_ids: &'a [String],
+    channels: &'a [&'a str],
+}
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Error {
+struct ErrorMessage {
     message: String,
 }
 
@@ -30,6 +50,34 @@ struct Snapshot {
     asks: Vec<(String, String)>,
 }
 
+// Sent on the matches channel for each trade, the initial message after
+// subscribing has type last_match.
+#[derive(Debug, Serialize, Deserialize)]
+struct Match {
+    trade_id: i64,
+    sequence: i64,
+    time: String,
+    product_id: String,
+    size: String,
+    price: String,
+    // The side of the maker order.
+    side: String,
+}
+
+// Sent on the ticker channel after each trade. The initial message after
+// subscribing only has the best bid and ask.
+#[derive(Debug, Serialize, Deserialize)]
+struct Ticker {
+    product_id: String,
+    sequence: i64,
+    trade_id: Option<i64>,
+    time: Option<String>,
+    price: String,
+    last_size: Option<String>,
+    // The side of the taker order.
+    side: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Heartbeat {
     product_id: String,
@@ -44,116 +92,549 @@ enum MessageType {
     Snapshot,
     Subscriptions,
     Heartbeat,
+    Match,
+    // Replayed on every subscription, this trade may have been seen already.
+    LastMatch,
+    Ticker,
 }
 
+// The per-product state maintained by the processor.
+struct Product {
+    spec: ProductSpec,
+    book_processor: BookProcessor,
+    trade_processor: TradeProcessor,
+    // The level2 channel is not sequenced, gaps are detected using the sequence
+    // numbers and trade ids from the heartbeat and matches channels.
+    sequence: Sequence,
+    trade_ids: Sequence,
+    // Set when a resubscription has been requested and the snapshot is pending.
+    resyncing: bool,
+    // The last book status reported through the event bus.
+    status: Result<(), NotLiveStatus>,
+}
+
+/// Maintains the books and trades of several products from a single gdax connection.
 pub struct JsonProcessor {
-    // Assumes a single product for now.
-    book_processor: RefCell<BookProcessor>,
+    product_ids: Vec<String>,
+    // The set of products is fixed at construction, only their state is mutated.
+    products: BTreeMap<String, RefCell<Product>>,
+    actions: RefCell<Vec<Action>>,
+    // The staleness is evaluated at the time of this clock when set, otherwise
+    // at the receive time of the messages.
+    clock: Option<Arc<dyn Clock>>,
+    bus: Rc<EventBus>,
 }
 
 impl JsonProcessor {
-    pub fn new() -> JsonProcessor {
+    /// Subscribes to the given products, their specs come from the registry.
+    pub fn new(product_ids: Vec<String>, registry: &Registry, bus: Rc<EventBus>) -> JsonProcessor {
+        let products = product_ids.iter()
+            .map(|product_id| {
+                let product = Product {
+                    spec: registry.spec(product_id),
+                    book_processor: BookProcessor::new(),
+                    trade_processor: TradeProcessor::new(),
+                    sequence: Sequence::new(),
+                    trade_ids: Sequence::new(),
+                    resyncing: false,
+                    status: Err(NotLiveStatus::InitialSnapshot),
+                };
+                (product_id.clone(), RefCell::new(product))
+            })
+            .collect();
         JsonProcessor {
-            book_processor: RefCell::new(BookProcessor::new()),
+            product_ids,
+            products,
+            actions: RefCell::new(Vec::new()),
+            clock: None,
+            bus,
+        }
+    }
+
+    /// Evaluates the staleness of the books at the time of the clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> JsonProcessor {
+        JsonProcessor { clock: Some(clock), ..self }
+    }
+
+    /// How long the books can go without updates before being reported as stale.
+    pub fn set_stale_after(&self, stale_after: Duration) {
+        for product in self.products.values() {
+            product.borrow_mut().book_processor.set_stale_after(stale_after);
+        }
+    }
+
+    /// The subscribed products.
+    pub fn product_ids(&self) -> &[String] {
+        &self.product_ids
+    }
+
+    /// Returns the book for a subscribed product, None if the product is unknown.
+    pub fn book(&self, product_id: &str) -> Option<Ref<'_, BookProcessor>> {
+        self.products.get(product_id)
+            .map(|product| Ref::map(product.borrow(), |product| &product.book_processor))
+    }
+
+    /// Returns the trade statistics for a subscribed product, None if the product is unknown.
+    pub fn trades(&self, product_id: &str) -> Option<Ref<'_, TradeProcessor>> {
+        self.products.get(product_id)
+            .map(|product| Ref::map(product.borrow(), |product| &product.trade_processor))
+    }
+
+    fn product(&self, product_id: &str) -> Result<&RefCell<Product>, Error> {
+        self.products.get(product_id)
+            .ok_or_else(|| Error::Protocol(format!("unexpected product {}", product_id)))
+    }
+
+    fn channel_message(type_: &str, product_ids: &[String], channels: &[&str]) -> Option<String> {
+        let message = Subscribe {
+            type_,
+            product_ids,
+            channels,
+        };
+        serde_json::to_string(&message).ok()
+    }
+
+    // Marks the book as not live and resubscribes to the level2 channel, the
+    // resulting snapshot will rebuild the book.
+    fn on_sequence_gap(&self, product_id: &str, product: &mut Product, error: &Error) {
+        warn!("{} {}", product_id, error);
+        product.book_processor.on_sequence_gap();
+        product.sequence.reset();
+        product.trade_ids.reset();
+        if !product.resyncing {
+            product.resyncing = true;
+            info!("{} resubscribing to level2", product_id);
+            let product_ids = [product_id.to_string()];
+            let mut actions = self.actions.borrow_mut();
+            for type_ in ["unsubscribe", "subscribe"].iter() {
+                if let Some(message) = JsonProcessor::channel_message(type_, &product_ids, &["level2"]) {
+                    actions.push(Action::Send(message));
+                }
+            }
+        }
+    }
+
+    // The time at which the staleness of the books is evaluated, the time of the
+    // message when there is no clock.
+    fn now(&self, time: &Time) -> Time {
+        match self.clock {
+            Some(ref clock) => clock.now(),
+            None => time.clone(),
+        }
+    }
+
+    // Emits a status change event if the book status differs from the last reported one.
+    fn check_status(&self, time: &Time, product_id: &str, product: &mut Product, events: &mut Vec<Event>) {
+        let status = product.book_processor.status(&self.now(time));
+        if status != product.status {
+            product.status = status.clone();
+            events.push(Event::StatusChange { product_id: product_id.to_string(), status });
         }
     }
 
-    fn parse_size(s: &str) -> Result<f64, String> {
-        let res: Result<f64, _> = s.parse();
-        res.map_err(|e| e.to_string())
+    // Parses all the levels of a message before any of them is applied, so that a
+    // message that cannot be parsed leaves the book untouched. The book then misses
+    // this message so it is rebuilt from a new snapshot.
+    fn parse_levels<'a, I>(&self, time: &Time, product_id: &str, product: &mut Product, levels: I, events: &mut Vec<Event>)
+            -> Result<Vec<(Side, Price, Quantity)>, Error>
+            where I: Iterator<Item=Result<(Side, &'a str, &'a str), Error>> {
+        let parsed = levels
+            .map(|level| {
+                let (side, price, size) = level?;
+                Ok((side, product.spec.parse_price(price)?, product.spec.parse_size(size)?))
+            })
+            .collect::<Result<Vec<_>, Error>>();
+        if let Err(ref error) = parsed {
+            self.on_sequence_gap(product_id, product, error);
+            self.check_status(time, product_id, product, events);
+        }
+        parsed
     }
 
-    fn message_type(&self, json: &serde_json::Value) -> Result<MessageType, String> {
-        match json {
-            &serde_json::Value::Object(ref map) => {
+    // Applies parsed levels to the book. A level that would overflow the book
+    // totals leaves the book incomplete, it is then rebuilt from a new snapshot.
+    fn apply_levels(&self, time: &Time, product_id: &str, product: &mut Product, levels: Vec<(Side, Price, Quantity)>, initial_snapshot: bool, events: &mut Vec<Event>)
+            -> Result<(), Error> {
+        for (side, price, size) in levels {
+            if let Err(error) = product.book_processor.on_update(time, side, price, size, initial_snapshot) {
+                self.on_sequence_gap(product_id, product, &error);
+                self.check_status(time, product_id, product, events);
+                return Err(error)
+            }
+            events.push(Event::LevelUpdate { product_id: product_id.to_string(), side, price, size });
+        }
+        Ok(())
+    }
+
+    fn process(&self, time: &Time, json: serde_json::Value, events: &mut Vec<Event>) -> Result<(), Error> {
+        match self.message_type(&json)? {
+            MessageType::Error => {
+                let error: ErrorMessage = serde_json::from_value(json)?;
+                error!("error: {:?}", error);
+                events.push(Event::ExchangeError { message: error.message });
+            },
+            MessageType::L2update => {
+                let l2update: L2update = serde_json::from_value(json)?;
+                let mut product = self.product(&l2update.product_id)?.borrow_mut();
+                let product = &mut *product;
+                let changes = l2update.changes.iter()
+                    .map(|(side, price, size)| Ok((Side::of_str(side)?, price.as_str(), size.as_str())));
+                let changes = self.parse_levels(time, &l2update.product_id, product, changes, events)?;
+                self.apply_levels(time, &l2update.product_id, product, changes, false, events)?;
+                self.check_status(time, &l2update.product_id, product, events);
+            },
+            MessageType::Snapshot => {
+                let snapshot: Snapshot = serde_json::from_value(json)?;
+                info!("processing snapshot for {}", snapshot.product_id);
+                let mut product = self.product(&snapshot.product_id)?.borrow_mut();
+                let product = &mut *product;
+                let levels = snapshot.bids.iter().map(|level| (Side::Buy, level))
+                    .chain(snapshot.asks.iter().map(|level| (Side::Sell, level)))
+                    .map(|(side, (price, size))| Ok((side, price.as_str(), size.as_str())));
+                let levels = self.parse_levels(time, &snapshot.product_id, product, levels, events)?;
+                product.resyncing = false;
+                product.book_processor.clear_on_snapshot();
+                events.push(Event::SnapshotStart { product_id: snapshot.product_id.clone() });
+                self.apply_levels(time, &snapshot.product_id, product, levels, true, events)?;
+                events.push(Event::SnapshotEnd { product_id: snapshot.product_id.clone() });
+                self.check_status(time, &snapshot.product_id, product, events);
+            },
+            MessageType::Subscriptions => {
+                let subscriptions: Subscriptions = serde_json::from_value(json)?;
+                info!("subscriptions: {:?}", subscriptions)
+            },
+            MessageType::Heartbeat => {
+                let heartbeat: Heartbeat = serde_json::from_value(json)?;
+                let mut product = self.product(&heartbeat.product_id)?.borrow_mut();
+                let gap = product.sequence.check_monotonic(heartbeat.sequence)
+                    .and_then(|()| product.trade_ids.check_last(heartbeat.last_trade_id));
+                if let Err(ref error) = gap {
+                    self.on_sequence_gap(&heartbeat.product_id, &mut product, error);
+                }
+                info!("{} heartbeat", heartbeat.product_id);
+                product.book_processor.log_summary();
+                product.trade_processor.log_summary();
+                if let Err(status) = product.book_processor.status(&self.now(time)) {
+                    warn!("{} book is not live: {:?}", heartbeat.product_id, status);
+                }
+                events.push(Event::Heartbeat { product_id: heartbeat.product_id.clone() });
+                self.check_status(time, &heartbeat.product_id, &mut product, events);
+                gap?;
+            },
+            MessageType::Match => {
+                let match_: Match = serde_json::from_value(json)?;
+                let mut product = self.product(&match_.product_id)?.borrow_mut();
+                let trade = JsonProcessor::match_trade(&product.spec, &match_)?;
+                let gap = product.sequence.check_monotonic(match_.sequence)
+                    .and_then(|()| product.trade_ids.check_next(trade.trade_id));
+                if let Err(ref error) = gap {
+                    self.on_sequence_gap(&trade.product_id, &mut product, error);
+                }
+                events.push(Event::Trade(trade.clone()));
+                product.trade_processor.on_trade(trade)?;
+                self.check_status(time, &match_.product_id, &mut product, events);
+                gap?;
+            },
+            MessageType::LastMatch => {
+                // The trade is only used as the last trade and as the starting point of
+                // the trade ids, it is not counted as it may have been processed before
+                // a resubscription.
+                let match_: Match = serde_json::from_value(json)?;
+                let mut product = self.product(&match_.product_id)?.borrow_mut();
+                let trade = JsonProcessor::match_trade(&product.spec, &match_)?;
+                if product.trade_ids.last().is_none() {
+                    product.trade_ids.check_monotonic(trade.trade_id)?;
+                }
+                product.trade_processor.on_last_trade(trade);
+            },
+            MessageType::Ticker => {
+                let ticker: Ticker = serde_json::from_value(json)?;
+                let mut product = self.product(&ticker.product_id)?.borrow_mut();
+                if let (Some(trade_id), Some(time), Some(size), Some(side)) = (ticker.trade_id, ticker.time, ticker.last_size, ticker.side) {
+                    // The trade is counted when received on the matches channel.
+                    let trade = Trade {
+                        trade_id,
+                        price: product.spec.parse_price(&ticker.price)?,
+                        size: product.spec.parse_size(&size)?,
+                        aggressor: Some(Side::of_str(&side)?),
+                        exchange_time: Time::parse_rfc3339(&time)?,
+                        product_id: ticker.product_id.clone(),
+                    };
+                    product.trade_processor.on_last_trade(trade);
+                }
+                debug!("{} ticker {} at sequence {}", ticker.product_id, ticker.price, ticker.sequence);
+            },
+        }
+        Ok(())
+    }
+
+    fn match_trade(spec: &ProductSpec, match_: &Match) -> Result<Trade, Error> {
+        Ok(Trade {
+            trade_id: match_.trade_id,
+            price: spec.parse_price(&match_.price)?,
+            size: spec.parse_size(&match_.size)?,
+            // The side is the one of the maker order.
+            aggressor: Some(Side::of_str(&match_.side)?.opposite()),
+            exchange_time: Time::parse_rfc3339(&match_.time)?,
+            product_id: match_.product_id.clone(),
+        })
+    }
+
+    fn message_type(&self, json: &serde_json::Value) -> Result<MessageType, Error> {
+        match *json {
+            serde_json::Value::Object(ref map) => {
                 match map.get("type") {
-                    Some(&serde_json::Value::String(ref message_type)) => {
+                    Some(serde_json::Value::String(message_type)) => {
                         match message_type.as_str() {
                             "heartbeat" => Ok(MessageType::Heartbeat),
                             "error" => Ok(MessageType::Error),
                             "l2update" => Ok(MessageType::L2update),
                             "snapshot" => Ok(MessageType::Snapshot),
                             "subscriptions" => Ok(MessageType::Subscriptions),
+                            "match" => Ok(MessageType::Match),
+                            "last_match" => Ok(MessageType::LastMatch),
+                            "ticker" => Ok(MessageType::Ticker),
                             _ => {
-                                Err(format!("unexpected type {}", message_type))
+                                Err(Error::Protocol(format!("unexpected type {}", message_type)))
                             }
                         }
                     }
                     Some(_) => {
-                        Err("json message has unexpected type".to_string())
+                        Err(Error::Protocol("json message has unexpected type".to_string()))
                     }
                     None => {
-                        Err("json message has missing type".to_string())
+                        Err(Error::Protocol("json message has missing type".to_string()))
                     }
                 }
             }
             _ => {
-                Err("json message is not an object".to_string())
+                Err(Error::Protocol("json message is not an object".to_string()))
             },
         }
     }
 }
 
 impl MessageProcessor for JsonProcessor {
-    fn subscribe_message(&self) -> Option<String> {
-        // TODO: ask for trades and handle the associated json message.
-        Some(String::from(r#"{"type": "subscribe", "product_ids": ["BTC-USD"], "channels": ["level2", "heartbeat"]}"#))
+    fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            server_name: "wss://ws-feed.gdax.com".to_string(),
+            subscribe_message: JsonProcessor::channel_message("subscribe", &self.product_ids, &["level2", "heartbeat", "matches", "ticker"]),
+        }
     }
 
-    fn server_name(&self) -> String {
-        "wss://ws-feed.gdax.com".to_string()
+    fn feed(&self) -> Feed {
+        Feed::Gdax
     }
 
-    fn on_message(&self, time: &Time, msg: &str) -> Result<(), String> {
-        let json: serde_json::Value = serde_json::from_str(&msg)
-            .map_err(|e| e.to_string())?;
-        match self.message_type(&json)? {
-            MessageType::Error => {
-                let error: Error = serde_json::from_value(json)
-                    .map_err(|e| e.to_string())?;
-                error!("error: {:?}", error)
-            },
-            MessageType::L2update => {
-                let l2update: L2update = serde_json::from_value(json)
-                    .map_err(|e| e.to_string())?;
-                let mut book_processor = self.book_processor.borrow_mut();
-                for &(ref side, ref price, ref size) in l2update.changes.iter() {
-                    let price = Price::parse_str(price)?;
-                    let size = JsonProcessor::parse_size(size)?;
-                    let side = Side::of_str(side)?;
-                    book_processor.on_update(time, side, price, size, false)
-                }
-            },
-            MessageType::Snapshot => {
-                let snapshot: Snapshot = serde_json::from_value(json)
-                    .map_err(|e| e.to_string())?;
-                info!("processing snapshot");
-                let mut book_processor = self.book_processor.borrow_mut();
-                book_processor.clear_on_snapshot();
-                for &(ref price, ref size) in snapshot.bids.iter() {
-                    let price = Price::parse_str(price)?;
-                    let size = JsonProcessor::parse_size(size)?;
-                    book_processor.on_update(time, Side::Buy, price, size, true);
-                }
-                for &(ref price, ref size) in snapshot.asks.iter() {
-                    let price = Price::parse_str(price)?;
-                    let size = JsonProcessor::parse_size(size)?;
-                    book_processor.on_update(time, Side::Sell, price, size, true);
-                }
-            },
-            MessageType::Subscriptions => {
-                let subscriptions: Subscriptions = serde_json::from_value(json)
-                    .map_err(|e| e.to_string())?;
-                info!("subscriptions: {:?}", subscriptions)
-            },
-            MessageType::Heartbeat => {
-                let _heartbeat: Heartbeat = serde_json::from_value(json)
-                    .map_err(|e| e.to_string())?;
-                self.book_processor.borrow().log_summary();
-            },
+    fn products(&self) -> Vec<String> {
+        self.product_ids.clone()
+    }
+
+    fn on_message(&self, time: &Time, msg: &str) -> Result<(), Error> {
+        let json: serde_json::Value = serde_json::from_str(msg)
+            .map_err(|e| Error::from(e).in_message(msg))?;
+        let mut events = Vec::new();
+        let result = self.process(time, json, &mut events);
+        self.bus.publish_processed(Feed::Gdax, time, events, &result);
+        result.map_err(|e| e.in_message(msg))
+    }
+
+    fn on_connect(&self, _epoch: u64) {
+        // Each connection starts with fresh snapshots.
+        for product in self.products.values() {
+            let mut product = product.borrow_mut();
+            product.book_processor.clear_on_snapshot();
+            product.sequence.reset();
+            product.trade_ids.reset();
+            product.resyncing = false;
+        }
+        self.actions.borrow_mut().clear();
+    }
+
+    fn on_tick(&self, now: &Time) {
+        let mut events = Vec::new();
+        for (product_id, product) in self.products.iter() {
+            self.check_status(now, product_id, &mut product.borrow_mut(), &mut events);
+        }
+        for event in events {
+            self.bus.publish(&FeedEvent { feed: Feed::Gdax, time: now.clone(), event });
         }
-        Ok(())
+    }
+
+    fn pending_actions(&self) -> Vec<Action> {
+        self.actions.borrow_mut().drain(..).collect()
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn product_routing_test() {
+        let processor = JsonProcessor::new(vec!["BTC-USD".to_string(), "ETH-USD".to_string()], &Registry::default(), Rc::new(EventBus::new()));
+        assert_eq!(
+            processor.endpoint().subscribe_message,
+            Some(r#"{"type":"subscribe","product_ids":["BTC-USD","ETH-USD"],"channels":["level2","heartbeat","matches","ticker"]}"#.to_string()));
+        let now = Time::now();
+        let snapshot = r#"{"type": "snapshot", "product_id": "ETH-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#;
+        assert_eq!(processor.on_message(&now, snapshot), Ok(()));
+        let update = r#"{"type": "l2update", "product_id": "ETH-USD", "changes": [["buy", "10.5", "0"]]}"#;
+        assert_eq!(processor.on_message(&now, update), Ok(()));
+        let update = r#"{"type": "l2update", "product_id": "LTC-USD", "changes": [["buy", "10.5", "0"]]}"#;
+        let error = processor.on_message(&now, update).unwrap_err();
+        assert_eq!(*error.root(), Error::Protocol("unexpected product LTC-USD".to_string()));
+        assert_eq!(error.message(), Some(update));
+        assert!(processor.book("ETH-USD").unwrap().status(&now).is_ok());
+        assert!(processor.book("BTC-USD").unwrap().status(&now).is_err());
+        assert!(processor.book("LTC-USD").is_none());
+    }
+
+    #[test]
+    fn match_test() {
+        let processor = JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let match_ = r#"{"type": "match", "trade_id": 10, "sequence": 50, "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8", "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1", "time": "2014-11-07T08:19:27.028459Z", "product_id": "BTC-USD", "size": "5.23512", "price": "400.23", "side": "sell"}"#;
+        assert_eq!(processor.on_message(&now, match_), Ok(()));
+        let trades = processor.trades("BTC-USD").unwrap();
+        assert_eq!(trades.trade_count(), 1);
+        assert_eq!(trades.buy_volume(), Quantity::parse_str("5.23512").unwrap());
+        let trade = trades.last_trade().unwrap();
+        assert_eq!(trade.trade_id, 10);
+        assert_eq!(trade.price, Price::parse_str("400.23").unwrap());
+        assert_eq!(trade.aggressor, Some(Side::Buy));
+        assert_eq!(trade.exchange_time.to_string(), "2014-11-07 08:19:27.028459000");
+    }
+
+    #[test]
+    fn last_match_test() {
+        let processor = JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let last_match = r#"{"type": "last_match", "trade_id": 10, "sequence": 50, "time": "2014-11-07T08:19:27.028459Z", "product_id": "BTC-USD", "size": "5.23512", "price": "400.23", "side": "sell"}"#;
+        let match_ = r#"{"type": "match", "trade_id": 11, "sequence": 51, "time": "2014-11-07T08:19:28.028459Z", "product_id": "BTC-USD", "size": "1", "price": "400.24", "side": "buy"}"#;
+        let ticker = r#"{"type": "ticker", "trade_id": 11, "sequence": 51, "time": "2014-11-07T08:19:28.028459Z", "product_id": "BTC-USD", "price": "400.24", "last_size": "1", "side": "sell", "best_bid": "400.23", "best_ask": "400.24"}"#;
+        assert_eq!(processor.on_message(&now, last_match), Ok(()));
+        assert_eq!(processor.trades("BTC-USD").unwrap().trade_count(), 0);
+        assert_eq!(processor.trades("BTC-USD").unwrap().last_trade().unwrap().trade_id, 10);
+        assert_eq!(processor.on_message(&now, match_), Ok(()));
+        assert_eq!(processor.on_message(&now, ticker), Ok(()));
+        // Resubscribing replays the last match, it is not counted again.
+        assert_eq!(processor.on_message(&now, last_match), Ok(()));
+        let trades = processor.trades("BTC-USD").unwrap();
+        assert_eq!(trades.trade_count(), 1);
+        assert_eq!(trades.volume(), Quantity::parse_str("1").unwrap());
+        assert_eq!(trades.last_trade().unwrap().trade_id, 11);
+        assert!(processor.pending_actions().is_empty());
+    }
+
+    #[test]
+    fn sequence_gap_test() {
+        let processor = JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let snapshot = r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#;
+        assert_eq!(processor.on_message(&now, snapshot), Ok(()));
+        let update = r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["buy", "10.5", "0"]]}"#;
+        assert_eq!(processor.on_message(&now, update), Ok(()));
+        let heartbeat = r#"{"type": "heartbeat", "product_id": "BTC-USD", "sequence": 90, "last_trade_id": 20, "time": "2014-11-07T08:19:28.464459Z"}"#;
+        assert_eq!(processor.on_message(&now, heartbeat), Ok(()));
+        let match_ = r#"{"type": "match", "trade_id": 21, "sequence": 91, "time": "2014-11-07T08:19:29.028459Z", "product_id": "BTC-USD", "size": "1", "price": "10.5", "side": "sell"}"#;
+        assert_eq!(processor.on_message(&now, match_), Ok(()));
+        assert!(processor.pending_actions().is_empty());
+        assert!(processor.book("BTC-USD").unwrap().status(&now).is_ok());
+
+        let match_ = r#"{"type": "match", "trade_id": 23, "sequence": 95, "time": "2014-11-07T08:19:29.528459Z", "product_id": "BTC-USD", "size": "1", "price": "10.5", "side": "sell"}"#;
+        let error = processor.on_message(&now, match_).unwrap_err();
+        assert_eq!(*error.root(), Error::SequenceGap { expected: 22, received: 23 });
+        // The trade is still counted.
+        assert_eq!(processor.trades("BTC-USD").unwrap().trade_count(), 2);
+        match processor.book("BTC-USD").unwrap().status(&now) {
+            Err(NotLiveStatus::SequenceGap) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(processor.pending_actions(), vec![
+            Action::Send(r#"{"type":"unsubscribe","product_ids":["BTC-USD"],"channels":["level2"]}"#.to_string()),
+            Action::Send(r#"{"type":"subscribe","product_ids":["BTC-USD"],"channels":["level2"]}"#.to_string()),
+        ]);
+        assert!(processor.pending_actions().is_empty());
+
+        assert_eq!(processor.on_message(&now, snapshot), Ok(()));
+        assert_eq!(processor.on_message(&now, update), Ok(()));
+        assert!(processor.book("BTC-USD").unwrap().status(&now).is_ok());
+    }
+
+    #[test]
+    fn large_size_test() {
+        let processor = JsonProcessor::new(vec!["SHIB-USD".to_string()], &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let snapshot = r#"{"type": "snapshot", "product_id": "SHIB-USD", "bids": [["0.00001", "500000000"], ["0.00002", "500000000"]], "asks": [["0.00003", "5000000000"]]}"#;
+        assert_eq!(processor.on_message(&now, snapshot), Ok(()));
+        let update = r#"{"type": "l2update", "product_id": "SHIB-USD", "changes": [["buy", "0.00001", "1000000000"]]}"#;
+        assert_eq!(processor.on_message(&now, update), Ok(()));
+        assert_eq!(processor.book("SHIB-USD").unwrap().total_bid_size(), Quantity::parse_str("1500000000").unwrap());
+        assert!(processor.book("SHIB-USD").unwrap().status(&now).is_ok());
+
+        // A size that overflows the book is an error and triggers a resync.
+        let update = r#"{"type": "l2update", "product_id": "SHIB-USD", "changes": [["sell", "0.00004", "9000000000000000000"], ["sell", "0.00005", "9000000000000000000"]]}"#;
+        assert!(processor.on_message(&now, update).is_err());
+        assert_eq!(processor.book("SHIB-USD").unwrap().status(&now), Err(NotLiveStatus::SequenceGap));
+        assert_eq!(processor.pending_actions().len(), 2);
+    }
 
+    #[test]
+    fn parse_error_test() {
+        let bus = Rc::new(EventBus::new());
+        let received = Rc::new(RefCell::new(Vec::new()));
+        {
+            let received = received.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| received.borrow_mut().push(event.event.clone())));
+        }
+        let processor = JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), bus);
+        let now = Time::now();
+        let snapshot = r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#;
+        assert_eq!(processor.on_message(&now, snapshot), Ok(()));
+        received.borrow_mut().clear();
+        let update = r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["buy", "10.5", "0"], ["sell", "11", "abc"]]}"#;
+        assert!(processor.on_message(&now, update).is_err());
+        // None of the changes has been applied or published.
+        let product_id = "BTC-USD".to_string();
+        assert_eq!(*received.borrow(), vec![Event::StatusChange { product_id: product_id.clone(), status: Err(NotLiveStatus::SequenceGap) }]);
+        assert_eq!(processor.book("BTC-USD").unwrap().best_bid(), Some((Price::parse_str("10.5").unwrap(), Quantity::parse_str("1.2").unwrap())));
+        assert_eq!(processor.book("BTC-USD").unwrap().status(&now), Err(NotLiveStatus::SequenceGap));
+        assert_eq!(processor.pending_actions().len(), 2);
+
+        received.borrow_mut().clear();
+        let snapshot = r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "abc"]]}"#;
+        assert!(processor.on_message(&now, snapshot).is_err());
+        assert!(received.borrow().is_empty());
+    }
+
+    #[test]
+    fn events_test() {
+        let bus = Rc::new(EventBus::new());
+        let received = Rc::new(RefCell::new(Vec::new()));
+        {
+            let received = received.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| received.borrow_mut().push(event.event.clone())));
+        }
+        let processor = JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), bus);
+        let now = Time::now();
+        let snapshot = r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#;
+        assert_eq!(processor.on_message(&now, snapshot), Ok(()));
+        let update = r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["buy", "10.5", "0"]]}"#;
+        assert_eq!(processor.on_message(&now, update), Ok(()));
+        let error = r#"{"type": "error", "message": "Failed to subscribe"}"#;
+        assert_eq!(processor.on_message(&now, error), Ok(()));
+        let product_id = "BTC-USD".to_string();
+        let price = |str| Price::parse_str(str).unwrap();
+        let size = |str| Quantity::parse_str(str).unwrap();
+        assert_eq!(*received.borrow(), vec![
+            Event::SnapshotStart { product_id: product_id.clone() },
+            Event::LevelUpdate { product_id: product_id.clone(), side: Side::Buy, price: price("10.5"), size: size("1.2") },
+            Event::LevelUpdate { product_id: product_id.clone(), side: Side::Sell, price: price("11"), size: size("3") },
+            Event::SnapshotEnd { product_id: product_id.clone() },
+            Event::LevelUpdate { product_id: product_id.clone(), side: Side::Buy, price: price("10.5"), size: size("0") },
+            Event::StatusChange { product_id: product_id.clone(), status: Ok(()) },
+            Event::ExchangeError { message: "Failed to subscribe".to_string() },
+        ]);
+    }
+}
@@ -1,12 +1,23 @@
 use serde_json;
 
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
-use book_processor::BookProcessor;
-use message_processor::MessageProcessor;
-use side::Side;
+use book_processor::{BookProcessor, NotLiveStatus};
+use clock::Clock;
+use error::Error;
+use event::{Auction, Event, EventBus, Feed, FeedEvent};
+use message_processor::{Action, MessageProcessor};
 use price::Price;
+use side::Side;
+use product::{ProductSpec, Registry};
+use quantity::Quantity;
+use sequence::Sequence;
 use time::Time;
+use trade::{Trade, TradeProcessor};
+use transport::Endpoint;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Change {
@@ -17,93 +28,509 @@ struct Change {
     side: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct TradeEvent {
+    tid: i64,
+    price: String,
+    amount: String,
+    // Either bid, ask or auction.
+    #[serde(rename = "makerSide")]
+    maker_side: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuctionOpen {
+    auction_open_ms: i64,
+    auction_time_ms: i64,
+    first_indicative_ms: i64,
+    last_cancel_time_ms: i64,
+}
+
+// The prices are missing when the auction failed or one side of the book is empty.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuctionIndicative {
+    eid: i64,
+    result: String,
+    time_ms: i64,
+    highest_bid_price: Option<String>,
+    lowest_ask_price: Option<String>,
+    collar_price: Option<String>,
+    indicative_price: Option<String>,
+    indicative_quantity: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuctionResult {
+    eid: i64,
+    result: String,
+    time_ms: i64,
+    highest_bid_price: Option<String>,
+    lowest_ask_price: Option<String>,
+    collar_price: Option<String>,
+    auction_price: Option<String>,
+    auction_quantity: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockTrade {
+    tid: i64,
+    price: String,
+    amount: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum UpdateEvent {
+    Change(Change),
+    Trade(TradeEvent),
+    AuctionOpen(AuctionOpen),
+    AuctionIndicative(AuctionIndicative),
+    AuctionResult(AuctionResult),
+    BlockTrade(BlockTrade),
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Update {
+    #[serde(rename = "eventId")]
+    event_id: i64,
+    socket_sequence: i64,
+    // Only set on updates that contain trades or auction events.
+    timestampms: Option<i64>,
+    events: Vec<UpdateEvent>,
+}
+
+// An update event once its prices and sizes have been parsed.
+enum Parsed {
+    Change { side: Side, price: Price, size: Quantity, initial_snapshot: bool },
+    Trade(Trade),
+    Event(Event),
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Heartbeat {
+    socket_sequence: i64,
+}
+
+/// Maintains the book and trades of a single gemini symbol.
 pub struct JsonProcessor {
-    // Assumes a single product for now.
+    // The gemini symbol, e.g. btcusd, a connection only carries a single product.
+    symbol: String,
+    product_id: String,
+    spec: ProductSpec,
     book_processor: RefCell<BookProcessor>,
+    trade_processor: RefCell<TradeProcessor>,
+    // Every message carries a socket_sequence that starts at 0 on each connection
+    // and increments by one.
+    socket_sequence: RefCell<Sequence>,
+    event_ids: RefCell<Sequence>,
+    // Set when a reconnection has been requested after a gap.
+    reconnecting: Cell<bool>,
+    actions: RefCell<Vec<Action>>,
+    // The last book status reported through the event bus.
+    status: RefCell<Result<(), NotLiveStatus>>,
+    // The staleness is evaluated at the time of this clock when set, otherwise
+    // at the receive time of the messages.
+    clock: Option<Arc<dyn Clock>>,
+    bus: Rc<EventBus>,
 }
 
 impl JsonProcessor {
-    pub fn new() -> JsonProcessor {
+    /// Connects to the market data of a symbol such as btcusd.
+    pub fn new(symbol: &str, registry: &Registry, bus: Rc<EventBus>) -> JsonProcessor {
+        let product_id = symbol.to_uppercase();
         JsonProcessor {
+            symbol: symbol.to_lowercase(),
+            spec: registry.spec(&product_id),
+            product_id,
             book_processor: RefCell::new(BookProcessor::new()),
+            trade_processor: RefCell::new(TradeProcessor::new()),
+            socket_sequence: RefCell::new(Sequence::new()),
+            event_ids: RefCell::new(Sequence::new()),
+            reconnecting: Cell::new(false),
+            actions: RefCell::new(Vec::new()),
+            status: RefCell::new(Err(NotLiveStatus::InitialSnapshot)),
+            clock: None,
+            bus,
         }
     }
 
-    fn parse_size(s: &str) -> Result<f64, String> {
-        let res: Result<f64, _> = s.parse();
-        res.map_err(|e| e.to_string())
+    /// Evaluates the staleness of the book at the time of the clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> JsonProcessor {
+        JsonProcessor { clock: Some(clock), ..self }
+    }
+
+    /// How long the book can go without updates before being reported as stale.
+    pub fn set_stale_after(&self, stale_after: Duration) {
+        self.book_processor.borrow_mut().set_stale_after(stale_after);
+    }
+
+    /// The product id used in the events, the upper case symbol.
+    pub fn product_id(&self) -> &str {
+        &self.product_id
     }
 
-    fn get_type(json: &serde_json::Value) -> Result<&str, String> {
-        match json {
-            &serde_json::Value::Object(ref map) => {
+    /// The book of the symbol.
+    pub fn book(&self) -> Ref<'_, BookProcessor> {
+        self.book_processor.borrow()
+    }
+
+    /// The trade statistics of the symbol.
+    pub fn trades(&self) -> Ref<'_, TradeProcessor> {
+        self.trade_processor.borrow()
+    }
+
+    fn get_type(json: &serde_json::Value) -> Result<&str, Error> {
+        match *json {
+            serde_json::Value::Object(ref map) => {
                 match map.get("type") {
-                    Some(&serde_json::Value::String(ref message_type)) => Ok(message_type),
-                    Some(_) => Err("json message has unexpected type".to_string()),
-                    None => Err("json message has missing type".to_string()),
+                    Some(serde_json::Value::String(message_type)) => Ok(message_type),
+                    Some(_) => Err(Error::Protocol("json message has unexpected type".to_string())),
+                    None => Err(Error::Protocol("json message has missing type".to_string())),
                 }
             }
-            _ => Err("json message is not an object".to_string()),
+            _ => Err(Error::Protocol("json message is not an object".to_string())),
         }
     }
 
-    fn get_events(&self, json: serde_json::Value) -> Result<Vec<Change>, String> {
-        // Remove this once NLL is in.
-        {
-            let message_type = JsonProcessor::get_type(&json)?;
-            if JsonProcessor::get_type(&json)? != "update" {
-                Err(format!("unexpected type {}", message_type))?
-            }
+    // Validates the sequencing of a message, a gap marks the book as not live
+    // and requests a reconnection as gemini has no way to resend a snapshot.
+    // The gap is returned once the message has been processed.
+    fn check_sequence(&self, socket_sequence: i64, event_id: Option<i64>) -> Result<(), Error> {
+        let mut sequence = self.socket_sequence.borrow_mut();
+        let mut event_ids = self.event_ids.borrow_mut();
+        if socket_sequence == 0 {
+            // This is a new connection, the initial events are about to rebuild the book.
+            sequence.reset();
+            event_ids.reset();
+            self.book_processor.borrow_mut().clear_on_snapshot();
+            self.reconnecting.set(false);
+        }
+        let mut gap = sequence.check_next(socket_sequence);
+        if let Some(event_id) = event_id {
+            gap = gap.and_then(|()| event_ids.check_monotonic(event_id));
+        }
+        if let Err(ref error) = gap {
+            self.on_sequence_gap(error);
+        }
+        gap
+    }
+
+    // Marks the book as not live and requests a reconnection, the initial events
+    // of the new connection will rebuild the book.
+    fn on_sequence_gap(&self, error: &Error) {
+        warn!("{} {}", self.product_id, error);
+        self.book_processor.borrow_mut().on_sequence_gap();
+        if !self.reconnecting.get() {
+            self.reconnecting.set(true);
+            info!("{} requesting a reconnection", self.product_id);
+            self.actions.borrow_mut().push(Action::Reconnect);
         }
-        let events = match json {
-            // We remove the 'events' key in order to avoid cloning the array.
-            serde_json::Value::Object(mut map) => map.remove("events"),
-            _ => Err("json message is not an object".to_string())?,
+    }
+
+    fn parse_change(&self, change: &Change) -> Result<Parsed, Error> {
+        let side = match change.side.as_str() {
+            "bid" => Side::Buy,
+            "ask" => Side::Sell,
+            _ => Err(Error::Parse(format!("unexpected side {}", change.side)))?,
+        };
+        Ok(Parsed::Change {
+            side,
+            price: self.spec.parse_price(&change.price)?,
+            size: self.spec.parse_size(&change.remaining)?,
+            initial_snapshot: change.reason == "initial",
+        })
+    }
+
+    fn parse_trade(&self, exchange_time: &Time, trade: &TradeEvent) -> Result<Parsed, Error> {
+        // The aggressor is on the opposite side of the maker.
+        let aggressor = match trade.maker_side.as_str() {
+            "bid" => Some(Side::Sell),
+            "ask" => Some(Side::Buy),
+            "auction" => None,
+            _ => Err(Error::Parse(format!("unexpected maker side {}", trade.maker_side)))?,
         };
-        let mut events = match events {
-            None => Err("no events")?,
-            Some(serde_json::Value::Array(events)) => events,
-            Some(_) => Err("unexpected event type")?,
+        Ok(Parsed::Trade(Trade {
+            product_id: self.product_id.clone(),
+            trade_id: trade.tid,
+            price: self.spec.parse_price(&trade.price)?,
+            size: self.spec.parse_size(&trade.amount)?,
+            aggressor,
+            exchange_time: exchange_time.clone(),
+        }))
+    }
+
+    fn parse_auction(&self, eid: i64, result: &str, price: &Option<String>, quantity: &Option<String>) -> Result<Auction, Error> {
+        Ok(Auction {
+            product_id: self.product_id.clone(),
+            auction_id: eid,
+            succeeded: result == "success",
+            price: match *price { Some(ref price) => Some(self.spec.parse_price(price)?), None => None },
+            quantity: match *quantity { Some(ref quantity) => Some(self.spec.parse_size(quantity)?), None => None },
+        })
+    }
+
+    fn parse_event(&self, exchange_time: &Time, update_event: &UpdateEvent) -> Result<Parsed, Error> {
+        match *update_event {
+            UpdateEvent::Change(ref change) => self.parse_change(change),
+            UpdateEvent::Trade(ref trade) => self.parse_trade(exchange_time, trade),
+            UpdateEvent::AuctionOpen(ref auction_open) => {
+                Ok(Parsed::Event(Event::AuctionOpen {
+                    product_id: self.product_id.clone(),
+                    auction_time: Time::of_millis(auction_open.auction_time_ms)?,
+                }))
+            },
+            UpdateEvent::AuctionIndicative(ref indicative) => {
+                let auction = self.parse_auction(indicative.eid, &indicative.result, &indicative.indicative_price, &indicative.indicative_quantity)?;
+                Ok(Parsed::Event(Event::AuctionIndicative(auction)))
+            },
+            UpdateEvent::AuctionResult(ref result) => {
+                let auction = self.parse_auction(result.eid, &result.result, &result.auction_price, &result.auction_quantity)?;
+                Ok(Parsed::Event(Event::AuctionResult(auction)))
+            },
+            // Block trades are negotiated off the book so they have no aggressor.
+            UpdateEvent::BlockTrade(ref block_trade) => {
+                Ok(Parsed::Trade(Trade {
+                    product_id: self.product_id.clone(),
+                    trade_id: block_trade.tid,
+                    price: self.spec.parse_price(&block_trade.price)?,
+                    size: self.spec.parse_size(&block_trade.amount)?,
+                    aggressor: None,
+                    exchange_time: exchange_time.clone(),
+                }))
+            },
+            UpdateEvent::Unknown => Ok(Parsed::Unknown),
+        }
+    }
+
+    // Emits a status change event if the book status differs from the last reported one.
+    fn check_status(&self, time: &Time, events: &mut Vec<Event>) {
+        let now = match self.clock {
+            Some(ref clock) => clock.now(),
+            None => time.clone(),
         };
-        let mut results = Vec::new();
-        for event in events.drain(..) {
-            let event_type_is_change = JsonProcessor::get_type(&event)? == "change";
-            // TODO: handle the other event types
-            if event_type_is_change {
-                let change: Change = serde_json::from_value(event)
-                    .map_err(|e| e.to_string())?;
-                results.push(change);
+        let status = self.book_processor.borrow().status(&now);
+        let mut last_status = self.status.borrow_mut();
+        if status != *last_status {
+            *last_status = status.clone();
+            events.push(Event::StatusChange { product_id: self.product_id.clone(), status });
+        }
+    }
+
+    fn process(&self, time: &Time, json: serde_json::Value, events: &mut Vec<Event>) -> Result<(), Error> {
+        let message_type = JsonProcessor::get_type(&json)?.to_string();
+        match message_type.as_str() {
+            "update" => (),
+            "heartbeat" => {
+                let heartbeat: Heartbeat = serde_json::from_value(json)?;
+                let gap = self.check_sequence(heartbeat.socket_sequence, None);
+                events.push(Event::Heartbeat { product_id: self.product_id.clone() });
+                self.check_status(time, events);
+                return gap
+            },
+            _ => Err(Error::Protocol(format!("unexpected type {}", message_type)))?,
+        }
+        let update: Update = serde_json::from_value(json)?;
+        let gap = self.check_sequence(update.socket_sequence, Some(update.event_id));
+        // Fall back on the reception time when the exchange does not provide one.
+        let exchange_time = match update.timestampms {
+            Some(timestampms) => Time::of_millis(timestampms)?,
+            None => time.clone(),
+        };
+        // All the events are parsed before any of them is applied so that an update
+        // that cannot be parsed leaves the book untouched. The book then misses this
+        // update so it is rebuilt on a new connection.
+        let parsed = update.events.iter()
+            .map(|update_event| self.parse_event(&exchange_time, update_event))
+            .collect::<Result<Vec<_>, Error>>();
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                self.on_sequence_gap(&error);
+                self.check_status(time, events);
+                return Err(error)
+            },
+        };
+        // The initial changes of a connection form the snapshot.
+        let mut in_snapshot = false;
+        for parsed_event in parsed {
+            match parsed_event {
+                Parsed::Change { side, price, size, initial_snapshot } => {
+                    if initial_snapshot && !in_snapshot {
+                        in_snapshot = true;
+                        events.push(Event::SnapshotStart { product_id: self.product_id.clone() });
+                    }
+                    // A change that would overflow the book totals leaves the book
+                    // incomplete, it is then rebuilt on a new connection.
+                    let updated = self.book_processor.borrow_mut().on_update(time, side, price, size, initial_snapshot);
+                    if let Err(error) = updated {
+                        self.on_sequence_gap(&error);
+                        self.check_status(time, events);
+                        return Err(error)
+                    }
+                    events.push(Event::LevelUpdate { product_id: self.product_id.clone(), side, price, size });
+                },
+                Parsed::Trade(trade) => {
+                    events.push(Event::Trade(trade.clone()));
+                    self.trade_processor.borrow_mut().on_trade(trade)?;
+                },
+                Parsed::Event(event) => {
+                    info!("{} {:?}", self.product_id, event);
+                    events.push(event);
+                },
+                Parsed::Unknown => {
+                    warn!("{} ignoring unknown event type", self.product_id)
+                },
             }
         }
-        Ok(results)
+        if in_snapshot {
+            events.push(Event::SnapshotEnd { product_id: self.product_id.clone() });
+        }
+        self.book_processor.borrow().log_summary();
+        self.check_status(time, events);
+        gap
     }
 }
 
 impl MessageProcessor for JsonProcessor {
-    fn subscribe_message(&self) -> Option<String> {
-        None
-    }
-
-    fn server_name(&self) -> String {
-        "wss://api.gemini.com/v1/marketdata/btcusd".to_string()
-    }
-
-    fn on_message(&self, time: &Time, msg: &str) -> Result<(), String> {
-        let json: serde_json::Value = serde_json::from_str(&msg)
-            .map_err(|e| e.to_string())?;
-        let mut book_processor = self.book_processor.borrow_mut();
-        for event in self.get_events(json)? {
-            let initial_snapshot = event.reason == "initial";
-            let side = match event.side.as_str() {
-                "bid" => Side::Buy,
-                "ask" => Side::Sell,
-                _ => Err(format!("unexpected side {}", event.side))?,
-            };
-            let price = Price::parse_str(&event.price)?;
-            let size = JsonProcessor::parse_size(&event.remaining)?;
-            book_processor.on_update(time, side, price, size, initial_snapshot)
+    fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            server_name: format!("wss://api.gemini.com/v1/marketdata/{}", self.symbol),
+            subscribe_message: None,
         }
-        book_processor.log_summary();
-        Ok(())
+    }
+
+    fn feed(&self) -> Feed {
+        Feed::Gemini
+    }
+
+    fn products(&self) -> Vec<String> {
+        vec![self.symbol.clone()]
+    }
+
+    fn on_message(&self, time: &Time, msg: &str) -> Result<(), Error> {
+        let json: serde_json::Value = serde_json::from_str(msg)
+            .map_err(|e| Error::from(e).in_message(msg))?;
+        let mut events = Vec::new();
+        let result = self.process(time, json, &mut events);
+        self.bus.publish_processed(Feed::Gemini, time, events, &result);
+        result.map_err(|e| e.in_message(msg))
+    }
+
+    fn on_connect(&self, _epoch: u64) {
+        // The initial events of the new connection rebuild the book.
+        self.book_processor.borrow_mut().clear_on_snapshot();
+        self.socket_sequence.borrow_mut().reset();
+        self.event_ids.borrow_mut().reset();
+        self.reconnecting.set(false);
+        self.actions.borrow_mut().clear();
+    }
+
+    fn on_tick(&self, now: &Time) {
+        let mut events = Vec::new();
+        self.check_status(now, &mut events);
+        for event in events {
+            self.bus.publish(&FeedEvent { feed: Feed::Gemini, time: now.clone(), event });
+        }
+    }
+
+    fn pending_actions(&self) -> Vec<Action> {
+        self.actions.borrow_mut().drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn events_test() {
+        let bus = Rc::new(EventBus::new());
+        let received = Rc::new(RefCell::new(Vec::new()));
+        {
+            let received = received.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| received.borrow_mut().push(event.event.clone())));
+        }
+        let processor = JsonProcessor::new("btcusd", &Registry::default(), bus);
+        assert_eq!(processor.endpoint().server_name, "wss://api.gemini.com/v1/marketdata/btcusd");
+        let now = Time::now();
+        let initial = r#"{"type":"update","eventId":5375461993,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"0.83372051","remaining":"0.83372051","side":"bid"},{"type":"change","reason":"initial","price":"3641.62","delta":"4.072","remaining":"4.072","side":"ask"}]}"#;
+        assert_eq!(processor.on_message(&now, initial), Ok(()));
+        let trade = r#"{"type":"update","eventId":5375547515,"timestamp":1547760288,"timestampms":1547760288001,"socket_sequence":1,"events":[{"type":"trade","tid":5375547515,"price":"3632.54","amount":"0.1362819142","makerSide":"ask"},{"type":"change","side":"ask","price":"3632.54","remaining":"0","delta":"-0.1362819142","reason":"trade"}]}"#;
+        assert_eq!(processor.on_message(&now, trade), Ok(()));
+        received.borrow_mut().clear();
+        let auction_open = r#"{"type":"update","eventId":5375547590,"timestamp":1510865040,"timestampms":1510865040122,"socket_sequence":2,"events":[{"type":"auction_open","auction_open_ms":1510865040000,"auction_time_ms":1510865640000,"first_indicative_ms":1510865280000,"last_cancel_time_ms":1510865580000}]}"#;
+        assert_eq!(processor.on_message(&now, auction_open), Ok(()));
+        let auction = r#"{"type":"update","eventId":5375547600,"timestamp":1510865640,"timestampms":1510865640122,"socket_sequence":3,"events":[{"type":"auction_indicative","eid":2248762586,"result":"success","time_ms":1510865640000,"highest_bid_price":"7730.69","lowest_ask_price":"7730.7","collar_price":"7730.695","indicative_price":"7750","indicative_quantity":"45.43325086"},{"type":"auction_result","eid":2248762586,"result":"failure","time_ms":1510865640000,"highest_bid_price":"7730.69","lowest_ask_price":"7730.7","collar_price":"7730.695"},{"type":"trade","tid":2248762587,"price":"7750","amount":"45.43325086","makerSide":"auction"},{"type":"block_trade","tid":2248762588,"price":"7750","amount":"1"}]}"#;
+        assert_eq!(processor.on_message(&now, auction), Ok(()));
+        let product_id = "BTCUSD".to_string();
+        let price = |str| Some(Price::parse_str(str).unwrap());
+        let quantity = |str| Some(Quantity::parse_str(str).unwrap());
+        let exchange_time = Time::of_millis(1510865640122).unwrap();
+        let events: Vec<_> = received.borrow().iter()
+            .filter(|event| !matches!(**event, Event::StatusChange { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(events, vec![
+            Event::AuctionOpen { product_id: product_id.clone(), auction_time: Time::of_millis(1510865640000).unwrap() },
+            Event::AuctionIndicative(Auction { product_id: product_id.clone(), auction_id: 2248762586, succeeded: true, price: price("7750"), quantity: quantity("45.43325086") }),
+            Event::AuctionResult(Auction { product_id: product_id.clone(), auction_id: 2248762586, succeeded: false, price: None, quantity: None }),
+            Event::Trade(Trade { product_id: product_id.clone(), trade_id: 2248762587, price: price("7750").unwrap(), size: quantity("45.43325086").unwrap(), aggressor: None, exchange_time: exchange_time.clone() }),
+            Event::Trade(Trade { product_id: product_id.clone(), trade_id: 2248762588, price: price("7750").unwrap(), size: quantity("1").unwrap(), aggressor: None, exchange_time }),
+        ]);
+        let trades = processor.trades();
+        assert_eq!(trades.trade_count(), 3);
+        assert_eq!(trades.buy_volume(), Quantity::parse_str("0.1362819142").unwrap());
+        let last_trade = trades.last_trade().unwrap();
+        assert_eq!(last_trade.product_id, "BTCUSD");
+        assert_eq!(last_trade.trade_id, 2248762588);
+        assert_eq!(last_trade.aggressor, None);
+        assert_eq!(last_trade.exchange_time.to_string(), "2017-11-16 20:54:00.122000000");
+        assert!(processor.pending_actions().is_empty());
+    }
+
+    #[test]
+    fn sequence_gap_test() {
+        let processor = JsonProcessor::new("btcusd", &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let initial = r#"{"type":"update","eventId":100,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"1","remaining":"1","side":"bid"},{"type":"change","reason":"initial","price":"3641.62","delta":"4","remaining":"4","side":"ask"}]}"#;
+        let change = r#"{"type":"update","eventId":101,"socket_sequence":1,"events":[{"type":"change","reason":"place","price":"3641.60","delta":"1","remaining":"1","side":"bid"}]}"#;
+        let heartbeat = r#"{"type":"heartbeat","socket_sequence":2}"#;
+        let late_change = r#"{"type":"update","eventId":102,"socket_sequence":4,"events":[{"type":"change","reason":"cancel","price":"3641.60","delta":"-1","remaining":"0","side":"bid"}]}"#;
+        assert_eq!(processor.on_message(&now, initial), Ok(()));
+        assert_eq!(processor.on_message(&now, change), Ok(()));
+        assert_eq!(processor.on_message(&now, heartbeat), Ok(()));
+        assert!(processor.book().status(&now).is_ok());
+        assert!(processor.pending_actions().is_empty());
+
+        let error = processor.on_message(&now, late_change).unwrap_err();
+        assert_eq!(*error.root(), Error::SequenceGap { expected: 3, received: 4 });
+        match processor.book().status(&now) {
+            Err(NotLiveStatus::SequenceGap) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(processor.pending_actions(), vec![Action::Reconnect]);
+
+        // After reconnecting, the sequence restarts from 0 with a new snapshot.
+        assert_eq!(processor.on_message(&now, initial), Ok(()));
+        assert_eq!(processor.on_message(&now, change), Ok(()));
+        assert!(processor.book().status(&now).is_ok());
+        assert!(processor.pending_actions().is_empty());
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let processor = JsonProcessor::new("btcusd", &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let initial = r#"{"type":"update","eventId":100,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"1","remaining":"1","side":"bid"}]}"#;
+        let change = r#"{"type":"update","eventId":101,"socket_sequence":1,"events":[{"type":"change","reason":"place","price":"3641.60","delta":"1","remaining":"1","side":"bid"},{"type":"change","reason":"place","price":"3641.62","delta":"1","remaining":"abc","side":"ask"}]}"#;
+        assert_eq!(processor.on_message(&now, initial), Ok(()));
+        assert!(processor.on_message(&now, change).is_err());
+        // None of the changes has been applied.
+        assert_eq!(processor.book().best_bid(), Some((Price::parse_str("3641.61").unwrap(), Quantity::parse_str("1").unwrap())));
+        assert_eq!(processor.book().status(&now), Err(NotLiveStatus::SequenceGap));
+        assert_eq!(processor.pending_actions(), vec![Action::Reconnect]);
     }
 }
@@ -0,0 +1,95 @@
+//! Market data handlers for cryptocurrency exchanges.
+//!
+//! A feed is decoded by a `MessageProcessor` (`gdax::JsonProcessor`,
+//! `gemini::JsonProcessor`) which maintains the order books and trades of its
+//! products and publishes normalized events on an `EventBus`. The messages are
+//! delivered to the processor by a `Transport`: a live `WebSocket` connection,
+//! a possibly compressed and rotated capture file written by a `Logger` or an in-memory list of messages.
+//!
+//! ```
+//! extern crate coin;
+//!
+//! use std::rc::Rc;
+//! use coin::event::{EventBus, FeedEvent};
+//! use coin::gemini;
+//! use coin::product::Registry;
+//! use coin::time::Time;
+//! use coin::transport::{InMemory, Transport};
+//!
+//! let bus = Rc::new(EventBus::new());
+//! bus.subscribe(Box::new(|event: &FeedEvent| println!("{:?}", event)));
+//! let processor = gemini::JsonProcessor::new("btcusd", &Registry::default(), bus);
+//! let message = r#"{"type":"update","eventId":1,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"0.5","remaining":"0.5","side":"bid"}]}"#;
+//! let transport = InMemory::new(vec![(Time::now(), message.to_string())]);
+//! transport.run(&processor).unwrap();
+//! assert!(processor.book().best_bid().is_some());
+//! ```
+
+#![warn(missing_docs)]
+
+extern crate chrono;
+extern crate ws;
+extern crate rand;
+
+#[macro_use] extern crate log;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate serde_path_to_error;
+extern crate signal_hook;
+extern crate toml;
+extern crate flate2;
+extern crate zstd;
+
+mod decimal;
+#[cfg(test)]
+mod test_util;
+
+/// Cross-venue crossed market detection.
+pub mod arbitrage;
+/// Order book of a single product.
+pub mod book_processor;
+/// Text and binary capture files, compressed, rotated and indexed.
+pub mod capture;
+/// The system clock and the clock pacing the replays.
+pub mod clock;
+/// TOML configuration of the feeds and sinks.
+pub mod config;
+/// Book of an instrument consolidated over several venues.
+pub mod consolidated;
+/// Errors raised by the feeds and transports.
+pub mod error;
+/// Normalized market data events and the bus they are published on.
+pub mod event;
+/// Running several feeds concurrently.
+pub mod feeds;
+/// GDAX (Coinbase) WebSocket feed.
+pub mod gdax;
+/// Gemini WebSocket feed.
+pub mod gemini;
+/// The protocol side of a feed and the capture logger.
+pub mod message_processor;
+/// Fixed-point prices.
+pub mod price;
+/// Per-product tick and lot sizes.
+pub mod product;
+/// Fixed-point sizes.
+pub mod quantity;
+/// Sequence number checks.
+pub mod sequence;
+/// Local WebSocket server streaming a capture.
+pub mod server;
+/// Clean shutdown on signals.
+pub mod shutdown;
+/// Buy and sell sides.
+pub mod side;
+/// Reconnection loop and its backoff.
+pub mod supervisor;
+/// Receive and exchange timestamps.
+pub mod time;
+/// Trades and trade statistics.
+pub mod trade;
+/// The transport side of a feed: replay files and in-memory messages.
+pub mod transport;
+/// Live WebSocket transport.
+pub mod websocket;
@@ -1,105 +1,254 @@
-extern crate chrono;
-extern crate ws;
+extern crate coin;
 extern crate env_logger;
-
 #[macro_use] extern crate log;
-extern crate serde;
-#[macro_use] extern crate serde_derive;
-extern crate serde_json;
 
 use std::env;
-use std::io::{BufRead, BufReader};
-use std::fs::File;
+use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod side;
-mod price;
-mod book_processor;
-mod message_processor;
-use message_processor::MessageProcessor;
-mod gdax;
-mod gemini;
-mod time;
+use coin::capture;
+use coin::capture::{CaptureReader, Header, Rotation};
+use coin::clock::{Clock, ReplayClock, Speed, SystemClock};
+use coin::config::Config;
+use coin::error::Error;
+use coin::event::{EventBus, FeedEvent};
+use coin::feeds;
+use coin::feeds::FeedSpec;
+use coin::message_processor::MessageProcessor;
+use coin::product::Registry;
+use coin::server::ReplayServer;
+use coin::shutdown::Shutdown;
+use coin::time::Time;
+use coin::transport::{MergedReplay, ReplayFile, Transport};
+use coin::websocket::WebSocket;
 
-fn connect(processor: &MessageProcessor) -> Result<(), ws::Error> {
-    ws::connect(processor.server_name(), |out| {
-        processor.subscribe_message().iter().for_each(|message| {
-            out.send(&message[..]).unwrap();
-            info!("succesfully sent subscription message");
-        });
+// How often the processors re-evaluate the staleness of the books during the
+// gaps of a replay, in recorded time.
+const REPLAY_TICK: Duration = Duration::from_millis(100);
 
-        move |msg| {
-            match msg {
-                ws::Message::Binary(vec) => {
-                    error!("unexpected binary message {:?}", vec);
-                }
-                ws::Message::Text(msg) => {
-                    let now = time::Time::now();
-                    match processor.on_message(&now, &msg) {
-                        Ok(()) => {
-                        }
-                        Err(error) => {
-                            error!("json parsing error {} {}", error, msg);
-                        }
-                    }
-                }
-            };
-            Ok(())
-        }
-    })
+// This returns a box as the MessageProcessor size is unknown at compile time.
+fn feed_processor(spec: &str, registry: &Registry, bus: Rc<EventBus>, clock: Arc<dyn Clock>) -> Result<Box<dyn MessageProcessor>, Error> {
+    FeedSpec::parse(spec)?.processor(registry, bus, clock)
+}
+
+fn header_processor(header: &Header, registry: &Registry, bus: Rc<EventBus>, clock: Arc<dyn Clock>) -> Result<Box<dyn MessageProcessor>, Error> {
+    info!("replaying {} captured by coin {} on {} from {}", header.feed, header.version, header.host, header.start);
+    FeedSpec::of_header(header).processor(registry, bus, clock)
 }
 
-fn replay(processor: &MessageProcessor, filename: &str) -> Result<(), String> {
-    let file = File::open(filename)
-        .map_err(|e| e.to_string())?;
-    let buf_reader = BufReader::new(file);
-    for line in buf_reader.lines() {
-        let line = line.map_err(|e| e.to_string())?;
-        let now = time::Time::parse(&line[..time::LEN])?;
-        match processor.on_message(&now, &line[time::LEN..]) {
-            Ok(()) => (),
-            Err(e) => error!("Error when parsing message {}", e),
+// The options of the replay commands followed by their arguments.
+struct ReplayOptions<'a> {
+    from: Option<&'a str>,
+    to: Option<&'a str>,
+    speed: Speed,
+    config: Option<&'a str>,
+    arguments: Vec<&'a str>,
+}
+
+// Returns None if an option has no value.
+fn replay_options(args: &[String]) -> Result<Option<ReplayOptions<'_>>, Error> {
+    let mut options = ReplayOptions { from: None, to: None, speed: Speed::Max, config: None, arguments: Vec::new() };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().map(|value| value.as_str());
+        match arg.as_str() {
+            "--from" => options.from = match value() { Some(value) => Some(value), None => return Ok(None) },
+            "--to" => options.to = match value() { Some(value) => Some(value), None => return Ok(None) },
+            "--speed" => options.speed = match value() { Some(value) => Speed::of_str(value)?, None => return Ok(None) },
+            "--config" => options.config = match value() { Some(value) => Some(value), None => return Ok(None) },
+            _ => options.arguments.push(arg.as_str()),
         }
     }
-    Ok(())
+    Ok(Some(options))
 }
 
-// This returns a box as the MessageProcessor size is unknown at compile time.
-fn feed_processor(feed_name: &str) -> Result<Box<MessageProcessor>, String> {
-    match feed_name {
-        "gdax" => Ok(Box::new(gdax::JsonProcessor::new())),
-        "gemini" => Ok(Box::new(gemini::JsonProcessor::new())),
-        _ => Err(format!("unsupported feed {}", feed_name)),
+impl<'a> ReplayOptions<'a> {
+    // The sinks and product specs of the configuration replace the default ones.
+    fn load_config(&self, bus: Rc<EventBus>, clock: Arc<dyn Clock>) -> Result<(Rc<EventBus>, Registry), Error> {
+        let config = match self.config {
+            Some(config) => Config::load(config)?,
+            None => return Ok((bus, Registry::default())),
+        };
+        let bus = Rc::new(EventBus::new());
+        for sink in config.sinks(clock) {
+            bus.subscribe(sink);
+        }
+        Ok((bus, config.products))
+    }
+
+    // A paced replay runs until interrupted, while one at full speed stops with
+    // ^C without finishing its output.
+    fn on_signals(&self, shutdown: &Shutdown) -> Result<(), Error> {
+        if self.speed != Speed::Max {
+            shutdown.on_signals()?;
+        }
+        Ok(())
+    }
+
+    fn range(&self, filename: &str) -> Result<(Option<Time>, Option<Time>), Error> {
+        let from = match self.from { Some(from) => Some(range_time(filename, from)?), None => None };
+        let to = match self.to { Some(to) => Some(range_time(filename, to)?), None => None };
+        Ok((from, to))
     }
 }
 
-fn main() {
-    env_logger::init().unwrap();
-    let args: Vec<_> = env::args().collect();
-    if args.len() <= 1 {
-        println!("Usage: {} real-time|log|replay", args[0]);
-        return
+// Parses a replay bound, either a full time or a time of day such as 14:30 on
+// the day the capture starts.
+fn range_time(filename: &str, str: &str) -> Result<Time, Error> {
+    if let Ok(time) = Time::parse(str) {
+        return Ok(time)
     }
-    if args[1] == "real-time" {
+    let start = match CaptureReader::open(filename)?.next() {
+        Some(record) => record?.time,
+        None => return Err(Error::Parse(format!("{} is empty", filename))),
+    };
+    start.at_time_of_day(str)
+}
+
+fn run(args: &[String], bus: Rc<EventBus>, shutdown: &Shutdown) -> Result<(), Error> {
+    if args[1] == "--config" {
         if args.len() != 3 {
-            println!("Usage: {} real-time gdax|gemini", args[0]);
-            return
+            println!("Usage: {} --config filename", args[0]);
+            return Ok(())
         }
-        let mut processor = feed_processor(&args[2]).unwrap();
-        connect(&mut *processor).unwrap();
+        // The configured sinks replace the default one.
+        let config = Config::load(&args[2])?;
+        shutdown.on_signals()?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let bus = EventBus::new();
+        for sink in config.sinks(clock.clone()) {
+            bus.subscribe(sink);
+        }
+        let result = feeds::run(&config.feeds, &config.products, &bus, clock, shutdown);
+        bus.end();
+        result
+    } else if args[1] == "real-time" {
+        if args.len() < 3 {
+            println!("Usage: {} real-time feed[:product,...]...", args[0]);
+            return Ok(())
+        }
+        let specs = args[2..].iter()
+            .map(|spec| FeedSpec::parse(spec))
+            .collect::<Result<Vec<_>, _>>()?;
+        shutdown.on_signals()?;
+        let result = feeds::run(&specs, &Registry::default(), &bus, Arc::new(SystemClock), shutdown);
+        bus.end();
+        result
     } else if args[1] == "log" {
-        if args.len() != 4 {
-            println!("Usage: {} log gdax|gemini filename", args[0]);
-            return
+        if args.len() != 4 && args.len() != 5 {
+            println!("Usage: {} log gdax|gemini[:product,...] filename[.gz|.zst] [never|hourly|daily|<size>MB]", args[0]);
+            return Ok(())
         }
-        let processor = feed_processor(&args[2]).unwrap();
-        let mut logger = processor.logger(&args[3]).unwrap();
-        connect(&mut logger).unwrap();
+        let rotation = match args.get(4) {
+            Some(rotation) => Rotation::of_str(rotation)?,
+            None => Rotation::Never,
+        };
+        let processor = feed_processor(&args[2], &Registry::default(), bus, Arc::new(SystemClock))?;
+        let logger = processor.rotating_logger(&args[3], rotation)?;
+        shutdown.on_signals()?;
+        WebSocket::new(logger.endpoint())
+            .with_shutdown(shutdown.clone())
+            .run(&logger)
     } else if args[1] == "replay" {
-        if args.len() != 4 {
-            println!("Usage: {} replay gdax|gemini filename", args[0]);
-            return
+        let options = match replay_options(&args[2..])? {
+            Some(options) if options.arguments.len() == 1 || options.arguments.len() == 2 => options,
+            _ => {
+                println!("Usage: {} replay [--from time] [--to time] [--speed 1|10|max] [--config filename] [gdax|gemini[:product,...]] filename", args[0]);
+                return Ok(())
+            },
+        };
+        // The processors and sinks evaluate the staleness at the time of the replay.
+        let clock = Arc::new(ReplayClock::new(options.speed).with_shutdown(shutdown.clone()));
+        let (bus, registry) = options.load_config(bus, clock.clone())?;
+        // The feed is only required for the captures written without a header.
+        let filename = options.arguments[options.arguments.len() - 1];
+        let processor = match (capture::read_header(filename)?, options.arguments.len()) {
+            (_, 2) => feed_processor(options.arguments[0], &registry, bus.clone(), clock.clone())?,
+            (Some(header), _) => header_processor(&header, &registry, bus.clone(), clock.clone())?,
+            (None, _) => return Err(Error::Parse(format!("{} has no header, the feed has to be given", filename))),
+        };
+        let (from, to) = options.range(filename)?;
+        options.on_signals(shutdown)?;
+        let result = ReplayFile::new(filename)
+            .with_range(from, to)
+            .with_clock(clock)
+            .with_tick(REPLAY_TICK)
+            .run(&*processor);
+        bus.end();
+        result
+    } else if args[1] == "merge-replay" {
+        let options = match replay_options(&args[2..])? {
+            Some(options) if !options.arguments.is_empty() => options,
+            _ => {
+                println!("Usage: {} merge-replay [--from time] [--to time] [--speed 1|10|max] [--config filename] filename...", args[0]);
+                return Ok(())
+            },
+        };
+        let clock = Arc::new(ReplayClock::new(options.speed).with_shutdown(shutdown.clone()));
+        let (bus, registry) = options.load_config(bus, clock.clone())?;
+        let filenames: Vec<_> = options.arguments.iter().map(|filename| filename.to_string()).collect();
+        let processors = filenames.iter()
+            .map(|filename| match capture::read_header(filename)? {
+                Some(header) => header_processor(&header, &registry, bus.clone(), clock.clone()),
+                None => Err(Error::Parse(format!("{} has no header, it cannot be merged", filename))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let processors: Vec<&dyn MessageProcessor> = processors.iter().map(|processor| &**processor).collect();
+        let (from, to) = options.range(&filenames[0])?;
+        options.on_signals(shutdown)?;
+        let result = MergedReplay::new(&filenames)
+            .with_range(from, to)
+            .with_clock(clock)
+            .with_tick(REPLAY_TICK)
+            .run(&processors);
+        bus.end();
+        result
+    } else if args[1] == "serve-replay" {
+        if args.len() != 4 && !(args.len() == 6 && args[2] == "--speed") {
+            println!("Usage: {} serve-replay [--speed 1|10|max] address filename", args[0]);
+            return Ok(())
         }
-        let mut processor = feed_processor(&args[2]).unwrap();
-        replay(&mut *processor, &args[3]).unwrap();
+        let speed = if args.len() == 6 { Speed::of_str(&args[3])? } else { Speed::Scaled(1.0) };
+        let (address, filename) = (&args[args.len() - 2], &args[args.len() - 1]);
+        let server = ReplayServer::bind(address, filename, speed)?.with_shutdown(shutdown.clone());
+        shutdown.on_signals()?;
+        // The feeds are pointed at the server with the endpoint of the configuration.
+        info!("serving {} on ws://{}", filename, server.local_addr()?);
+        server.run()
+    } else if args[1] == "convert" {
+        if args.len() != 4 && args.len() != 5 {
+            println!("Usage: {} convert input output[.bin][.gz|.zst] [never|hourly|daily|<size>MB]", args[0]);
+            return Ok(())
+        }
+        let rotation = match args.get(4) {
+            Some(rotation) => Rotation::of_str(rotation)?,
+            None => Rotation::Never,
+        };
+        let count = capture::convert(&args[2], &args[3], rotation)?;
+        info!("converted {} records from {} to {}", count, args[2], args[3]);
+        Ok(())
+    } else {
+        println!("Usage: {} --config|real-time|log|replay|merge-replay|serve-replay|convert", args[0]);
+        Ok(())
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let args: Vec<_> = env::args().collect();
+    let bus = Rc::new(EventBus::new());
+    bus.subscribe(Box::new(|event: &FeedEvent| debug!("{:?}", event)));
+    if args.len() <= 1 {
+        println!("Usage: {} --config|real-time|log|replay|merge-replay|serve-replay|convert", args[0]);
+        return
+    }
+    // The commands that run until interrupted install the signal handler, the
+    // others keep the default handling so that they can be stopped with ^C.
+    let shutdown = Shutdown::new();
+    if let Err(error) = run(&args, bus, &shutdown) {
+        eprintln!("{}", error);
+        process::exit(1);
     }
 }
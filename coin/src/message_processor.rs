@@ -1,66 +1,160 @@
 use std;
-use std::cell::RefCell;
-use std::io::Write;
-use std::fs::File;
+use std::cell::{Cell, RefCell};
+use capture::{CaptureWriter, Header, Record, Rotation};
+use error::Error;
+use event::Feed;
 use time;
+use transport::Endpoint;
 
 enum LoggerKind {
-    File(RefCell<File>),
+    File(Box<RefCell<CaptureWriter>>),
     Stdout,
 }
 
+/// Captures the messages of a feed to a file or stdout, and optionally forwards them to a processor.
 pub struct Logger {
     kind: LoggerKind,
-    subscribe_message: Option<String>,
-    server_name: String,
+    // Describes the capture, this is where the endpoint is kept.
+    header: Header,
+    // The connection the messages are received on.
+    epoch: Cell<u64>,
+    // The processor the messages are forwarded to once logged, if any.
+    processor: Option<Box<dyn MessageProcessor>>,
 }
 
-// TODO: split this into two traits: MessageProcesor and JsonConnection
+impl Logger {
+    /// Processes the messages in addition to logging them.
+    pub fn forward_to(self, processor: Box<dyn MessageProcessor>) -> Logger {
+        Logger { processor: Some(processor), ..self }
+    }
+
+    /// Records the endpoint actually connected to, e.g. when the server is overridden.
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> Logger {
+        self.header = Header::new(self.header.feed, self.header.products.clone(), &endpoint);
+        if let LoggerKind::File(ref writer) = self.kind {
+            writer.borrow_mut().set_header(self.header.clone());
+        }
+        self
+    }
+}
+
+/// Requests that a processor can make to the connection it runs on.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Send a message to the server.
+    Send(String),
+    /// Close the connection and open a new one.
+    Reconnect,
+}
+
+/// Decodes the messages of a feed, whatever the transport they are received on.
 pub trait MessageProcessor {
-    fn server_name(&self) -> String;
-    fn subscribe_message(&self) -> Option<String>;
-    fn on_message(&self, &time::Time, &str) -> Result<(), String>;
+    /// The server to connect to and how to subscribe to it when running live.
+    fn endpoint(&self) -> Endpoint;
+    /// The exchange the messages come from.
+    fn feed(&self) -> Feed;
+    /// The products of the feed as given to the processor, e.g. the gemini symbols.
+    fn products(&self) -> Vec<String>;
+    /// Processes a message received at `now`, the errors are reported but the feed goes on.
+    fn on_message(&self, now: &time::Time, msg: &str) -> Result<(), Error>;
 
+    /// Called before each connection to the server, the processor should reset
+    /// any state that depends on the previous connection.
+    fn on_connect(&self, _epoch: u64) {
+    }
+
+    /// Called by the transport between messages, so that the state that depends
+    /// on the current time, e.g. the book staleness, is updated even when the
+    /// feed is quiet.
+    fn on_tick(&self, _now: &time::Time) {
+    }
+
+    /// Returns the actions requested since the last call, this is called after each message.
+    fn pending_actions(&self) -> Vec<Action> {
+        Vec::new()
+    }
+
+    /// A logger capturing to a single file.
     fn logger(&self, filename: &str) -> Result<Logger, std::io::Error> {
+        self.rotating_logger(filename, Rotation::Never)
+    }
+
+    /// The file is compressed when its name ends with .gz or .zst and binary
+    /// when it ends with .bin. Each file starts with a header describing the feed.
+    fn rotating_logger(&self, filename: &str, rotation: Rotation) -> Result<Logger, std::io::Error> {
+        let header = Header::new(self.feed(), self.products(), &self.endpoint());
         let kind =
             if filename == "stdout" {
                 LoggerKind::Stdout
             } else {
-                let file = File::create(filename)?;
-                LoggerKind::File(RefCell::new(file))
+                let mut writer = CaptureWriter::create(filename, rotation)?;
+                writer.set_header(header.clone());
+                LoggerKind::File(Box::new(RefCell::new(writer)))
             };
         Ok(Logger {
-            kind: kind,
-            subscribe_message: self.subscribe_message(),
-            server_name: self.server_name(),
+            kind,
+            header,
+            epoch: Cell::new(1),
+            processor: None,
         })
     }
 }
 
 // This makes it possible to create a logger on Logger, not sure how useful this would be.
 impl MessageProcessor for Logger {
-    fn subscribe_message(&self) -> Option<String> {
-        self.subscribe_message.clone()
+    fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            server_name: self.header.server_name.clone(),
+            subscribe_message: self.header.subscribe_message.clone(),
+        }
+    }
+
+    fn feed(&self) -> Feed {
+        self.header.feed
     }
 
-    fn server_name(&self) -> String {
-        self.server_name.clone()
+    fn products(&self) -> Vec<String> {
+        self.header.products.clone()
     }
 
-    fn on_message(&self, now: &time::Time, message: &str) -> Result<(), String> {
+    fn on_message(&self, now: &time::Time, message: &str) -> Result<(), Error> {
         match self.kind {
-            LoggerKind::File(ref file) => {
-                file.borrow_mut().write_all(now.to_string().as_bytes())
-                    .map_err(|e| e.to_string())?;
-                file.borrow_mut().write_all(message.as_bytes())
-                    .map_err(|e| e.to_string())?;
-                file.borrow_mut().write_all(b"\n")
-                    .map_err(|e| e.to_string())?;
+            LoggerKind::File(ref writer) => {
+                let record = Record {
+                    time: now.clone(),
+                    feed: Some(self.header.feed),
+                    epoch: self.epoch.get(),
+                    payload: message.to_string(),
+                };
+                writer.borrow_mut().write(&record)?;
             },
             LoggerKind::Stdout => {
-                print!("{} {}\n", now.to_string(), message);
+                println!("{} {}", now, message);
             },
         }
-        Ok(())
+        match self.processor {
+            Some(ref processor) => processor.on_message(now, message),
+            None => Ok(()),
+        }
+    }
+
+    fn on_connect(&self, epoch: u64) {
+        self.epoch.set(epoch);
+        if let Some(ref processor) = self.processor {
+            processor.on_connect(epoch);
+        }
+    }
+
+    fn on_tick(&self, now: &time::Time) {
+        if let Some(ref processor) = self.processor {
+            processor.on_tick(now);
+        }
+    }
+
+    fn pending_actions(&self) -> Vec<Action> {
+        match self.processor {
+            Some(ref processor) => processor.pending_actions(),
+            None => Vec::new(),
+        }
     }
 }
@@ -1,82 +1,232 @@
 use std;
+use std::ops::{Add, Mul, Sub};
 
-// Price encoded as int with 6 digits.
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+use serde;
+
+use decimal;
+use error::Error;
+use quantity::Quantity;
+
+/// Price encoded as int with SCALE digits.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Price(i64);
 
 impl Price {
-    // Assumes ascii encoding and non-negative prices.
-    pub fn parse_str(str: &str) -> Result<Price, String> {
-        let mut pre_dot: i64 = 0;
-        let mut seen_dot = false;
-        let mut post_dot: i64 = 0;
-        let mut post_dot_cnt: i64 = 0;
-        for c in str.chars() {
-            if c == '.' {
-                seen_dot = true;
-                continue
-            }
-            if c < '0' || c > '9' {
-                Err(format!("unable to parse as price {}", str))?
-            }
-            let digit = match c.to_digit(10) {
-                Some(digit) => digit as i64,
-                None => Err(format!("unable to parse as price {}", str))?
-            };
-            if seen_dot {
-                if post_dot_cnt < 6 {
-                    post_dot = 10 * post_dot + digit;
-                    post_dot_cnt += 1;
-                } else {
-                    if digit != 0 {
-                        Err(format!("unable to parse as price (too many digits) {}", str))?
-                    }
-                }
-            } else {
-                pre_dot = 10 * pre_dot + digit;
-            }
+    /// The number of digits after the dot.
+    pub const SCALE: u32 = 9;
+
+    /// Assumes ascii encoding, accepts up to SCALE significant digits after the dot.
+    pub fn parse_str(str: &str) -> Result<Price, Error> {
+        Price::parse_str_with_precision(str, Price::SCALE)
+    }
+
+    /// Parses a price that is expected to have at most `precision` significant
+    /// digits after the dot, trailing zeros are always accepted.
+    pub fn parse_str_with_precision(str: &str, precision: u32) -> Result<Price, Error> {
+        if precision > Price::SCALE {
+            Err(Error::Parse(format!("unsupported price precision {}", precision)))?
         }
-        for _ in post_dot_cnt..6 {
-            post_dot *= 10;
+        decimal::parse(str, Price::SCALE, precision)
+            .map(Price)
+            .map_err(|e| Error::Parse(format!("unable to parse as price {}", e)))
+    }
+
+    /// Rounds to the nearest representable price.
+    pub fn of_float(f: f64) -> Price {
+        Price((f * 10f64.powi(Price::SCALE as i32)).round() as i64)
+    }
+
+    /// The price as a float, e.g. to compute ratios.
+    pub fn to_float(self) -> f64 {
+        let Price(p) = self;
+        p as f64 / 10f64.powi(Price::SCALE as i32)
+    }
+
+    /// Whether the price is a multiple of a positive tick size.
+    pub fn is_on_tick(&self, tick_size: &Price) -> bool {
+        let (&Price(p), &Price(tick_size)) = (self, tick_size);
+        tick_size > 0 && p % tick_size == 0
+    }
+
+    /// Rounds to the nearest multiple of the tick size, ties are rounded up.
+    pub fn round_to_tick(&self, tick_size: &Price) -> Price {
+        let (&Price(p), &Price(tick_size)) = (self, tick_size);
+        if tick_size <= 0 {
+            return *self
         }
-        Ok(Price(pre_dot * 1_000_000 + post_dot))
+        Price((p + tick_size / 2).div_euclid(tick_size) * tick_size)
+    }
+
+    /// The price halfway between self and other, rounded down.
+    pub fn midpoint(&self, other: &Price) -> Price {
+        let (&Price(p1), &Price(p2)) = (self, other);
+        Price(p1 + (p2 - p1).div_euclid(2))
     }
 
-    fn to_float(&self) -> f64 {
-        let &Price(p) = self;
-        p as f64 / 1e6
+    /// The average of the prices weighted by their quantities, rounded to the
+    /// nearest representable price. Returns None if the total quantity is not
+    /// positive or if the quantities are too large to be weighted.
+    pub fn weighted_average<I: IntoIterator<Item=(Price, Quantity)>>(iter: I) -> Option<Price> {
+        let mut notional: i128 = 0;
+        let mut total: i128 = 0;
+        for (Price(p), quantity) in iter {
+            notional = notional.checked_add((p as i128).checked_mul(quantity.scaled_units())?)?;
+            total = total.checked_add(quantity.scaled_units())?;
+        }
+        if total <= 0 {
+            return None
+        }
+        // Round half away from zero.
+        let rounded =
+            if notional >= 0 {
+                notional + total / 2
+            } else {
+                notional - total / 2
+            };
+        Some(Price((rounded / total) as i64))
+    }
+
+    /// Moves the price by a number of ticks, ticks can be negative.
+    pub fn offset(&self, ticks: i64, tick_size: &Price) -> Price {
+        *self + *tick_size * ticks
+    }
+}
+
+impl Add for Price {
+    type Output = Price;
+
+    fn add(self, other: Price) -> Price {
+        Price(self.0 + other.0)
+    }
+}
+
+impl Sub for Price {
+    type Output = Price;
+
+    fn sub(self, other: Price) -> Price {
+        Price(self.0 - other.0)
+    }
+}
+
+// Multiplying by an integer is used to express a number of ticks.
+impl Mul<i64> for Price {
+    type Output = Price;
+
+    fn mul(self, n: i64) -> Price {
+        Price(self.0 * n)
     }
 }
 
 impl std::fmt::Display for Price {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.to_float().fmt(f)
+        decimal::fmt(self.0, Price::SCALE, f)
     }
 }
 
 impl std::fmt::Debug for Price {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.to_float().fmt(f)
+        decimal::fmt(self.0, Price::SCALE, f)
+    }
+}
+
+// Prices are serialized as decimal strings, the same way exchanges send them.
+impl serde::Serialize for Price {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Price {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Price, D::Error> {
+        let str: std::borrow::Cow<'de, str> = serde::Deserialize::deserialize(deserializer)?;
+        Price::parse_str(&str).map_err(serde::de::Error::custom)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json;
 
     #[test]
     fn parse_test() {
         assert_eq!(Price::parse_str("0"), Ok(Price(0)));
         assert_eq!(Price::parse_str("0.0"), Ok(Price(0)));
-        assert_eq!(Price::parse_str("0.000001"), Ok(Price(1)));
-        assert_eq!(Price::parse_str("0.00001"), Ok(Price(10)));
-        assert_eq!(Price::parse_str("0.000010"), Ok(Price(10)));
-        assert_eq!(Price::parse_str("0.00001000"), Ok(Price(10)));
-        assert_eq!(Price::parse_str("1"), Ok(Price(1_000_000)));
-        assert_eq!(Price::parse_str("1.0"), Ok(Price(1_000_000)));
-        assert_eq!(Price::parse_str("1.000000"), Ok(Price(1_000_000)));
-        assert_eq!(Price::parse_str("1.000000000"), Ok(Price(1_000_000)));
-        assert_eq!(Price::parse_str("42.00300000"), Ok(Price(42_003_000)));
-        assert_eq!(Price::parse_str("42.00300100"), Ok(Price(42_003_001)));
+        assert_eq!(Price::parse_str("0.000001"), Ok(Price(1_000)));
+        assert_eq!(Price::parse_str("0.00001"), Ok(Price(10_000)));
+        assert_eq!(Price::parse_str("0.000010"), Ok(Price(10_000)));
+        assert_eq!(Price::parse_str("0.00001000"), Ok(Price(10_000)));
+        assert_eq!(Price::parse_str("1"), Ok(Price(1_000_000_000)));
+        assert_eq!(Price::parse_str("1.0"), Ok(Price(1_000_000_000)));
+        assert_eq!(Price::parse_str("1.000000"), Ok(Price(1_000_000_000)));
+        assert_eq!(Price::parse_str("1.000000000"), Ok(Price(1_000_000_000)));
+        assert_eq!(Price::parse_str("42.00300000"), Ok(Price(42_003_000_000)));
+        assert_eq!(Price::parse_str("42.00300100"), Ok(Price(42_003_001_000)));
+        assert_eq!(Price::parse_str("0.00000123"), Ok(Price(1_230)));
+        assert_eq!(Price::parse_str("-1.5"), Ok(Price(-1_500_000_000)));
+        assert!(Price::parse_str("0.0000000001").is_err());
+        assert!(Price::parse_str("1,5").is_err());
+        assert_eq!(Price::parse_str_with_precision("0.03245000", 5), Ok(Price(32_450_000)));
+        assert!(Price::parse_str_with_precision("0.032451", 5).is_err());
+        assert!(Price::parse_str_with_precision("1", 10).is_err());
+    }
+
+    #[test]
+    fn tick_test() {
+        let tick = Price::parse_str("0.01").unwrap();
+        assert!(Price::parse_str("6500.01").unwrap().is_on_tick(&tick));
+        assert!(!Price::parse_str("6500.015").unwrap().is_on_tick(&tick));
+        assert!(Price::parse_str("-6500.01").unwrap().is_on_tick(&tick));
+        assert!(!Price(1).is_on_tick(&Price(0)));
+        assert_eq!(Price::parse_str("6500.014").unwrap().round_to_tick(&tick), Price::parse_str("6500.01").unwrap());
+        assert_eq!(Price::parse_str("6500.015").unwrap().round_to_tick(&tick), Price::parse_str("6500.02").unwrap());
+        assert_eq!(Price::parse_str("-0.014").unwrap().round_to_tick(&tick), Price::parse_str("-0.01").unwrap());
+        assert_eq!(Price::parse_str("-0.016").unwrap().round_to_tick(&tick), Price::parse_str("-0.02").unwrap());
+    }
+
+    #[test]
+    fn arithmetic_test() {
+        assert_eq!(Price(10).midpoint(&Price(20)), Price(15));
+        assert_eq!(Price(10).midpoint(&Price(21)), Price(15));
+        assert_eq!(Price(21).midpoint(&Price(10)), Price(15));
+        assert_eq!(Price(21) - Price(10), Price(11));
+        assert_eq!(Price(10) - Price(21), Price(-11));
+        assert_eq!(Price(10) + Price(21), Price(31));
+        assert_eq!(Price(10) * -3, Price(-30));
+        let q = |str| Quantity::parse_str(str).unwrap();
+        assert_eq!(Price::weighted_average(vec![(Price(10), q("1")), (Price(20), q("3"))]), Some(Price(18)));
+        assert_eq!(Price::weighted_average(vec![(Price(10), q("1")), (Price(11), q("1"))]), Some(Price(11)));
+        assert_eq!(Price::weighted_average(vec![(Price(10), q("2")), (Price(11), q("1"))]), Some(Price(10)));
+        assert_eq!(Price::weighted_average(vec![(Price(10), q("0"))]), None);
+        assert_eq!(Price(10).offset(3, &Price(5)), Price(25));
+        assert_eq!(Price(10).offset(-3, &Price(5)), Price(-5));
+        assert_eq!(Price::of_float(42.0030000006), Price(42_003_000_001));
+        assert_eq!(Price::of_float(0.1), Price(100_000_000));
+    }
+
+    #[test]
+    fn format_test() {
+        let p = |str| Price::parse_str(str).unwrap();
+        assert_eq!(p("6500.01").to_string(), "6500.01");
+        assert_eq!(format!("{:?}", p("0.1")), "0.1");
+        assert_eq!(p("0.3").to_string(), "0.3");
+        assert_eq!(p("42.00300100").to_string(), "42.003001");
+        assert_eq!(p("0.000000001").to_string(), "0.000000001");
+        assert_eq!(p("-1.5").to_string(), "-1.5");
+        assert_eq!(p("100").to_string(), "100");
+        assert_eq!((p("0.1") + p("0.2")).to_string(), "0.3");
+    }
+
+    #[test]
+    fn serde_test() {
+        let p = |str| Price::parse_str(str).unwrap();
+        assert_eq!(serde_json::to_string(&p("6500.01")).unwrap(), r#""6500.01""#);
+        assert_eq!(serde_json::from_str::<Price>(r#""6500.01""#).unwrap(), p("6500.01"));
+        assert_eq!(serde_json::from_str::<Vec<Price>>(r#"["1", "-0.5"]"#).unwrap(), vec![p("1"), p("-0.5")]);
+        assert!(serde_json::from_str::<Price>(r#""abc""#).is_err());
+        assert!(serde_json::from_str::<Price>("1.5").is_err());
+        let mut levels = std::collections::HashMap::new();
+        levels.insert(p("1.5"), 1);
+        assert_eq!(levels.get(&p("1.50")), Some(&1));
     }
 }
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use decimal::decimals;
+use error::Error;
+use price::Price;
+use quantity::Quantity;
+
+/// The trading rules of a product on an exchange.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProductSpec {
+    /// Prices are multiples of the tick size.
+    pub tick_size: Price,
+    /// The maximum number of significant digits after the dot in prices.
+    pub price_precision: u32,
+    /// Sizes are multiples of the lot size.
+    pub lot_size: Quantity,
+    /// The maximum number of significant digits after the dot in sizes, strict
+    /// specs parse the sizes at this scale so that whole lots can be large.
+    pub size_precision: u32,
+    /// When set, prices and sizes that do not follow the spec are rejected.
+    /// Otherwise off-tick prices are rounded and off-lot sizes accepted with a
+    /// warning, as exchanges can change their rules at any time.
+    pub strict: bool,
+}
+
+impl ProductSpec {
+    /// Builds a spec from the decimal representations of the tick and lot sizes,
+    /// the price and size precisions are the ones of the tick and lot sizes.
+    pub fn of_strs(tick_size: &str, lot_size: &str) -> Result<ProductSpec, Error> {
+        Ok(ProductSpec {
+            tick_size: Price::parse_str(tick_size)?,
+            price_precision: decimals(tick_size),
+            lot_size: Quantity::parse_str(lot_size)?,
+            size_precision: decimals(lot_size),
+            strict: false,
+        })
+    }
+
+    /// Parses a price and checks that it is compatible with the precision and tick size.
+    pub fn parse_price(&self, str: &str) -> Result<Price, Error> {
+        if !self.strict {
+            let price = Price::parse_str(str)?;
+            if !price.is_on_tick(&self.tick_size) {
+                warn!("price {} is not a multiple of the tick size {}, rounding it", str, self.tick_size);
+                return Ok(self.round_price(&price))
+            }
+            return Ok(price)
+        }
+        let price = Price::parse_str_with_precision(str, self.price_precision)?;
+        if !price.is_on_tick(&self.tick_size) {
+            Err(Error::Parse(format!("price {} is not a multiple of the tick size {}", str, self.tick_size)))?
+        }
+        Ok(price)
+    }
+
+    /// Rounds a price to the nearest tick.
+    pub fn round_price(&self, price: &Price) -> Price {
+        price.round_to_tick(&self.tick_size)
+    }
+
+    /// Parses a size and checks that it is compatible with the lot size.
+    pub fn parse_size(&self, str: &str) -> Result<Quantity, Error> {
+        if !self.strict {
+            let size = Quantity::parse_str(str)?;
+            if !size.is_multiple_of(self.lot_size) {
+                warn!("size {} is not a multiple of the lot size {}", str, self.lot_size);
+            }
+            return Ok(size)
+        }
+        let size = Quantity::parse_str_with_scale(str, self.size_precision)?;
+        if !size.is_multiple_of(self.lot_size) {
+            Err(Error::Parse(format!("size {} is not a multiple of the lot size {}", str, self.lot_size)))?
+        }
+        Ok(size)
+    }
+}
+
+// The default spec accepts anything that can be represented.
+impl Default for ProductSpec {
+    fn default() -> ProductSpec {
+        ProductSpec {
+            tick_size: Price::parse_str("0.000000001").unwrap(),
+            price_precision: Price::SCALE,
+            lot_size: Quantity::parse_str("0.0000000001").unwrap(),
+            size_precision: Quantity::SCALE,
+            strict: false,
+        }
+    }
+}
+
+/// The product specs indexed by the product id used by the exchange, e.g.
+/// BTC-USD for gdax and BTCUSD for gemini.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registry {
+    specs: HashMap<String, ProductSpec>,
+}
+
+// (product id, tick size, lot size)
+const BUILTIN_SPECS: &[(&str, &str, &str)] = &[
+    ("BTC-USD", "0.01", "0.00000001"),
+    ("BTC-EUR", "0.01", "0.00000001"),
+    ("BTC-GBP", "0.01", "0.00000001"),
+    ("ETH-USD", "0.01", "0.00000001"),
+    ("ETH-EUR", "0.01", "0.00000001"),
+    ("ETH-BTC", "0.00001", "0.00000001"),
+    ("LTC-USD", "0.01", "0.00000001"),
+    ("LTC-EUR", "0.01", "0.00000001"),
+    ("LTC-BTC", "0.00001", "0.00000001"),
+    ("BCH-USD", "0.01", "0.00000001"),
+    ("BCH-BTC", "0.00001", "0.00000001"),
+    ("BTCUSD", "0.01", "0.0000000001"),
+    ("ETHUSD", "0.01", "0.000001"),
+    ("ETHBTC", "0.00001", "0.000001"),
+    ("ZECUSD", "0.01", "0.000001"),
+    ("ZECBTC", "0.00001", "0.000001"),
+    ("ZECETH", "0.0001", "0.000001"),
+];
+
+impl Registry {
+    /// An empty registry, see default for the builtin specs.
+    pub fn new() -> Registry {
+        Registry {
+            specs: HashMap::new(),
+        }
+    }
+
+    /// Adds or replaces the spec of a product.
+    pub fn insert(&mut self, product_id: &str, spec: ProductSpec) {
+        self.specs.insert(product_id.to_string(), spec);
+    }
+
+    /// The spec of a product, None if it is not registered.
+    pub fn get(&self, product_id: &str) -> Option<&ProductSpec> {
+        self.specs.get(product_id)
+    }
+
+    /// Returns the spec for a product, falling back on the permissive default
+    /// spec for unknown products.
+    pub fn spec(&self, product_id: &str) -> ProductSpec {
+        match self.specs.get(product_id) {
+            Some(spec) => spec.clone(),
+            None => {
+                warn!("no spec for product {}, using the default one", product_id);
+                ProductSpec::default()
+            }
+        }
+    }
+}
+
+// The registry of the products known to be traded on the supported exchanges,
+// the configuration can override these specs.
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry::new();
+        for &(product_id, tick_size, lot_size) in BUILTIN_SPECS.iter() {
+            let spec = ProductSpec::of_strs(tick_size, lot_size)
+                .expect("invalid builtin product spec");
+            registry.insert(product_id, spec);
+        }
+        registry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spec_test() {
+        let registry = Registry::default();
+        let mut btc_usd = registry.spec("BTC-USD");
+        btc_usd.strict = true;
+        assert_eq!(btc_usd.price_precision, 2);
+        assert_eq!(btc_usd.parse_price("6500.01000000"), Price::parse_str("6500.01"));
+        assert!(btc_usd.parse_price("6500.015").is_err());
+        assert_eq!(btc_usd.round_price(&Price::parse_str("6500.015").unwrap()), Price::parse_str("6500.02").unwrap());
+        assert_eq!(btc_usd.parse_size("0.00100000"), Quantity::parse_str("0.001"));
+
+        let mut eth_btc = registry.spec("ETH-BTC");
+        eth_btc.strict = true;
+        assert_eq!(eth_btc.parse_price("0.03245000"), Price::parse_str("0.03245"));
+        assert!(eth_btc.parse_price("0.032455").is_err());
+
+        let mut eth_usd = registry.spec("ETHUSD");
+        eth_usd.strict = true;
+        assert!(eth_usd.parse_size("0.0000001").is_err());
+
+        let btc_usd = registry.spec("BTCUSD");
+        assert_eq!(btc_usd.size_precision, 10);
+        assert_eq!(btc_usd.parse_size("0.1362819142"), Quantity::parse_str("0.1362819142"));
+        assert!(btc_usd.parse_size("-0.1").is_err());
+
+        let mut shib_usd = ProductSpec::of_strs("0.00000001", "1").unwrap();
+        shib_usd.strict = true;
+        assert_eq!(shib_usd.parse_size("5000000000000"), Quantity::parse_str("5000000000000"));
+        assert!(shib_usd.parse_size("0.5").is_err());
+
+        let unknown = registry.spec("SHIB-USD");
+        assert_eq!(unknown, ProductSpec::default());
+        assert_eq!(unknown.parse_price("0.000012345"), Price::parse_str("0.000012345"));
+        assert!(registry.get("SHIB-USD").is_none());
+    }
+
+    #[test]
+    fn lenient_test() {
+        let registry = Registry::default();
+        let btc_usd = registry.spec("BTC-USD");
+        assert!(!btc_usd.strict);
+        assert_eq!(btc_usd.parse_price("6500.015"), Price::parse_str("6500.02"));
+        assert_eq!(btc_usd.parse_size("0.0000000015"), Quantity::parse_str("0.0000000015"));
+        assert!(btc_usd.parse_price("abc").is_err());
+        assert!(btc_usd.parse_size("-1").is_err());
+    }
+}
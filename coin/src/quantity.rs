@@ -0,0 +1,184 @@
+use std;
+use std::cmp::Ordering;
+
+use decimal;
+use error::Error;
+
+/// Quantity encoded as an int with its own number of digits after the dot, so
+/// that the sizes of a product are only as precise as its lot size requires and
+/// the large sizes of small-cap pairs still fit. SCALE is the finest precision
+/// supported. The scale is kept as small as possible so that equal quantities
+/// have the same representation.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Quantity {
+    units: i64,
+    scale: u32,
+}
+
+impl Quantity {
+    /// The maximum number of digits after the dot.
+    pub const SCALE: u32 = 10;
+
+    /// The null quantity.
+    pub fn zero() -> Quantity {
+        Quantity::default()
+    }
+
+    /// Parses a quantity with as many digits after the dot as given, up to SCALE.
+    pub fn parse_str(str: &str) -> Result<Quantity, Error> {
+        Quantity::parse_str_with_scale(str, decimal::decimals(str).min(Quantity::SCALE + 1))
+    }
+
+    /// Parses a quantity that is expected to have at most `scale` significant
+    /// digits after the dot, e.g. the lot precision of a product. The feeds only
+    /// send sizes and amounts so negative quantities are rejected, differences
+    /// computed with checked_sub can still be negative.
+    pub fn parse_str_with_scale(str: &str, scale: u32) -> Result<Quantity, Error> {
+        if scale > Quantity::SCALE {
+            Err(Error::Parse(format!("unsupported quantity scale {} for {}", scale, str)))?
+        }
+        if str.starts_with('-') {
+            Err(Error::Parse(format!("negative quantity {}", str)))?
+        }
+        decimal::parse(str, scale, scale)
+            .map(|units| Quantity::new(units, scale))
+            .map_err(|e| Error::Parse(format!("unable to parse as quantity {}", e)))
+    }
+
+    // Drops the trailing zeros of the units.
+    fn new(mut units: i64, mut scale: u32) -> Quantity {
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        Quantity { units, scale }
+    }
+
+    /// The number of 10^-SCALE units in the quantity, this cannot overflow.
+    pub fn scaled_units(self) -> i128 {
+        self.units as i128 * 10i128.pow(Quantity::SCALE - self.scale)
+    }
+
+    // The quantity of a number of 10^-SCALE units, None if it is out of range.
+    fn of_scaled_units(units: i128) -> Option<Quantity> {
+        let mut scale = Quantity::SCALE;
+        let mut units = units;
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        if units > i64::MAX as i128 || units < i64::MIN as i128 {
+            return None
+        }
+        Some(Quantity { units: units as i64, scale })
+    }
+
+    /// Whether the quantity is a multiple of a positive lot size.
+    pub fn is_multiple_of(self, lot_size: Quantity) -> bool {
+        lot_size.units > 0 && self.scaled_units() % lot_size.scaled_units() == 0
+    }
+
+    /// Whether the quantity is null, e.g. a removed level.
+    pub fn is_zero(self) -> bool {
+        self.units == 0
+    }
+
+    /// The sum of two quantities, None if it is out of range.
+    pub fn checked_add(self, other: Quantity) -> Option<Quantity> {
+        Quantity::of_scaled_units(self.scaled_units() + other.scaled_units())
+    }
+
+    /// The difference of two quantities, None if it is out of range.
+    pub fn checked_sub(self, other: Quantity) -> Option<Quantity> {
+        Quantity::of_scaled_units(self.scaled_units() - other.scaled_units())
+    }
+
+    /// The sum of the quantities, None if it is out of range.
+    pub fn checked_sum<I: IntoIterator<Item=Quantity>>(iter: I) -> Option<Quantity> {
+        iter.into_iter()
+            .try_fold(0i128, |total, quantity| total.checked_add(quantity.scaled_units()))
+            .and_then(Quantity::of_scaled_units)
+    }
+
+    /// Adds a quantity to a total such as a volume, failing on overflow.
+    pub fn accumulate(&mut self, other: Quantity) -> Result<(), Error> {
+        *self = self.checked_add(other)
+            .ok_or_else(|| Error::Protocol(format!("quantity overflow adding {} to {}", other, self)))?;
+        Ok(())
+    }
+
+    /// The quantity as a float, e.g. to compute ratios.
+    pub fn to_float(self) -> f64 {
+        self.units as f64 / 10f64.powi(self.scale as i32)
+    }
+}
+
+impl Ord for Quantity {
+    fn cmp(&self, other: &Quantity) -> Ordering {
+        self.scaled_units().cmp(&other.scaled_units())
+    }
+}
+
+impl PartialOrd for Quantity {
+    fn partial_cmp(&self, other: &Quantity) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        decimal::fmt(self.units, self.scale, f)
+    }
+}
+
+impl std::fmt::Debug for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        decimal::fmt(self.units, self.scale, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Quantity::parse_str("0"), Ok(Quantity::zero()));
+        assert_eq!(Quantity::parse_str("0.0000000001"), Ok(Quantity { units: 1, scale: 10 }));
+        assert_eq!(Quantity::parse_str("1.5"), Ok(Quantity { units: 15, scale: 1 }));
+        assert_eq!(Quantity::parse_str("0.1362819142").unwrap().scaled_units(), 1_362_819_142);
+        assert_eq!(Quantity::parse_str("5.235120000000"), Ok(Quantity { units: 523_512, scale: 5 }));
+        assert!(Quantity::parse_str("-0.25").is_err());
+        assert!(Quantity::parse_str("0.00000000001").is_err());
+        assert!(Quantity::parse_str("abc").is_err());
+        assert_eq!(Quantity::parse_str_with_scale("1.25", 2), Quantity::parse_str("1.25"));
+        assert!(Quantity::parse_str_with_scale("1.255", 2).is_err());
+        assert!(Quantity::parse_str_with_scale("1", 11).is_err());
+        // The sizes of small-cap pairs are large but not precise.
+        assert_eq!(Quantity::parse_str("5000000000000").unwrap().to_string(), "5000000000000");
+        assert!(Quantity::parse_str_with_scale("5000000000000", 10).is_err());
+    }
+
+    #[test]
+    fn arithmetic_test() {
+        let q = |str| Quantity::parse_str(str).unwrap();
+        // 0.1 + 0.2 - 0.3 is not exactly 0 with floats.
+        assert!(q("0.1").checked_add(q("0.2")).unwrap().checked_sub(q("0.3")).unwrap().is_zero());
+        assert_eq!(Quantity::checked_sum(vec![q("0.1"), q("0.2"), q("0.3")]), Some(q("0.6")));
+        assert_eq!(q("1000000000").checked_add(q("2000000000.5")), Some(q("3000000000.5")));
+        assert_eq!(Quantity { units: i64::MAX, scale: 0 }.checked_add(q("1")), None);
+        assert_eq!(q("1000000000000000000").checked_add(q("0.5")), None);
+        let mut total = q("9000000000000000000");
+        assert!(total.accumulate(q("9000000000000000000")).is_err());
+        assert_eq!(total, q("9000000000000000000"));
+        assert!(q("1.5") > q("1.25"));
+        assert!(q("1000000000") > q("0.5"));
+        assert_eq!(q("1.5").to_string(), "1.5");
+        assert_eq!(q("0.0000000001").to_string(), "0.0000000001");
+        assert_eq!(q("0.1").checked_sub(q("0.3")).unwrap().to_string(), "-0.2");
+        assert_eq!(q("1.5").to_float(), 1.5);
+        assert!(q("1.5").is_multiple_of(q("0.5")));
+        assert!(!q("1.5").is_multiple_of(q("0.2")));
+        assert!(!q("1.5").is_multiple_of(q("0")));
+    }
+}
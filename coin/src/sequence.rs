@@ -0,0 +1,102 @@
+use error::Error;
+
+/// Tracks the sequence numbers attached to the messages of an exchange feed.
+pub struct Sequence {
+    last: Option<i64>,
+}
+
+impl Default for Sequence {
+    fn default() -> Sequence {
+        Sequence::new()
+    }
+}
+
+impl Sequence {
+    /// A sequence that accepts any first number.
+    pub fn new() -> Sequence {
+        Sequence {
+            last: None,
+        }
+    }
+
+    /// The last sequence number accepted.
+    pub fn last(&self) -> Option<i64> {
+        self.last
+    }
+
+    /// Forgets the last sequence number, the next one is accepted unconditionally.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// Checks that seq directly follows the last sequence number. On a gap the
+    /// tracking resumes from seq, out of order numbers are discarded.
+    pub fn check_next(&mut self, seq: i64) -> Result<(), Error> {
+        match self.last {
+            Some(last) if seq <= last => {
+                Err(Error::OutOfOrder { last, received: seq })
+            },
+            Some(last) if seq != last + 1 => {
+                self.last = Some(seq);
+                Err(Error::SequenceGap { expected: last + 1, received: seq })
+            },
+            _ => {
+                self.last = Some(seq);
+                Ok(())
+            },
+        }
+    }
+
+    /// Checks that seq does not go backwards, gaps are allowed.
+    pub fn check_monotonic(&mut self, seq: i64) -> Result<(), Error> {
+        match self.last {
+            Some(last) if seq < last => {
+                Err(Error::OutOfOrder { last, received: seq })
+            },
+            _ => {
+                self.last = Some(seq);
+                Ok(())
+            },
+        }
+    }
+
+    /// Checks seq against the sender's view of the last sequence number.
+    pub fn check_last(&mut self, seq: i64) -> Result<(), Error> {
+        match self.last {
+            Some(last) if seq < last => {
+                Err(Error::OutOfOrder { last, received: seq })
+            },
+            Some(last) if seq > last => {
+                self.last = Some(seq);
+                Err(Error::SequenceGap { expected: last + 1, received: seq })
+            },
+            _ => {
+                self.last = Some(seq);
+                Ok(())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sequence_test() {
+        let mut sequence = Sequence::new();
+        assert!(sequence.check_next(42).is_ok());
+        assert!(sequence.check_next(43).is_ok());
+        assert_eq!(sequence.check_next(43), Err(Error::OutOfOrder { last: 43, received: 43 }));
+        assert_eq!(sequence.check_next(45), Err(Error::SequenceGap { expected: 44, received: 45 }));
+        assert!(sequence.check_next(46).is_ok());
+        assert!(sequence.check_monotonic(46).is_ok());
+        assert!(sequence.check_monotonic(50).is_ok());
+        assert!(sequence.check_monotonic(49).is_err());
+        assert!(sequence.check_last(50).is_ok());
+        assert_eq!(sequence.check_last(51), Err(Error::SequenceGap { expected: 51, received: 51 }));
+        assert_eq!(sequence.last(), Some(51));
+        sequence.reset();
+        assert!(sequence.check_last(10).is_ok());
+    }
+}
@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+use ws;
+
+use capture::CaptureReader;
+use clock::{ReplayClock, Speed};
+use error::Error;
+use shutdown::Shutdown;
+
+// How often the server checks for a shutdown request.
+const SHUTDOWN_CHECK_MS: u64 = 100;
+
+/// Streams the messages of a capture to each client, as the exchange server
+/// would. When the capture records a subscription message, a client has to
+/// send the same subscription before anything is streamed to it. The stream
+/// starts over from the beginning of the capture for each client and the
+/// connection is left open once the capture has been streamed.
+pub struct ReplayServer {
+    socket: ws::WebSocket<Factory>,
+    shutdown: Shutdown,
+}
+
+struct Factory {
+    filename: String,
+    speed: Speed,
+    subscribe_message: Option<String>,
+}
+
+impl ws::Factory for Factory {
+    type Handler = Client;
+
+    fn connection_made(&mut self, out: ws::Sender) -> Client {
+        Client {
+            out,
+            filename: self.filename.clone(),
+            speed: self.speed,
+            subscribe_message: self.subscribe_message.clone(),
+            stop: Shutdown::new(),
+            streaming: false,
+        }
+    }
+}
+
+impl ReplayServer {
+    /// Binds to an address such as 127.0.0.1:8080, port 0 picks a free port.
+    pub fn bind(address: &str, filename: &str, speed: Speed) -> Result<ReplayServer, Error> {
+        let speed = speed.validate()?;
+        let reader = CaptureReader::open(filename)?;
+        let subscribe_message = match reader.header() {
+            Some(header) => header.subscribe_message.clone(),
+            None => {
+                warn!("{} has no header, the clients will not be asked for a subscription", filename);
+                None
+            },
+        };
+        let factory = Factory { filename: filename.to_string(), speed, subscribe_message };
+        let socket = ws::WebSocket::new(factory)?.bind(address)?;
+        Ok(ReplayServer { socket, shutdown: Shutdown::new() })
+    }
+
+    /// The server stops once the shutdown is requested.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> ReplayServer {
+        ReplayServer { shutdown, ..self }
+    }
+
+    /// The address the server listens on, e.g. when bound to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Serves the clients until the shutdown is requested.
+    pub fn run(self) -> Result<(), Error> {
+        let broadcaster = self.socket.broadcaster();
+        let shutdown = self.shutdown.clone();
+        let stopped = Shutdown::new();
+        let watcher = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while !shutdown.wait(Duration::from_millis(SHUTDOWN_CHECK_MS)) {
+                    if stopped.is_requested() {
+                        return
+                    }
+                }
+                info!("stopping the replay server on shutdown");
+                if let Err(error) = broadcaster.shutdown() {
+                    error!("unable to stop the replay server: {}", error);
+                }
+            })
+        };
+        let result = self.socket.run();
+        stopped.request();
+        let _ = watcher.join();
+        result.map(|_| ()).map_err(Error::from)
+    }
+}
+
+struct Client {
+    out: ws::Sender,
+    filename: String,
+    speed: Speed,
+    subscribe_message: Option<String>,
+    // Stops the streaming thread once the client is gone.
+    stop: Shutdown,
+    streaming: bool,
+}
+
+impl Client {
+    fn stream(&mut self) {
+        self.streaming = true;
+        let out = self.out.clone();
+        let filename = self.filename.clone();
+        let clock = ReplayClock::new(self.speed).with_shutdown(self.stop.clone());
+        thread::spawn(move || {
+            let result = CaptureReader::open(&filename).and_then(|reader| {
+                for record in reader {
+                    let record = record?;
+                    if !clock.advance_to(&record.time) {
+                        return Ok(())
+                    }
+                    out.send(record.payload)?;
+                }
+                info!("streamed all of {} to client {}", filename, out.connection_id());
+                Ok(())
+            });
+            if let Err(error) = result {
+                error!("unable to stream {}: {}", filename, error);
+                let _ = out.close(ws::CloseCode::Error);
+            }
+        });
+    }
+}
+
+// Compares the subscriptions as json so that the formatting does not matter.
+fn same_subscription(expected: &str, received: &str) -> bool {
+    match (serde_json::from_str::<serde_json::Value>(expected), serde_json::from_str::<serde_json::Value>(received)) {
+        (Ok(expected), Ok(received)) => expected == received,
+        _ => expected == received,
+    }
+}
+
+impl ws::Handler for Client {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        info!("client {} connected", self.out.connection_id());
+        if self.subscribe_message.is_none() {
+            self.stream();
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        if self.streaming {
+            debug!("ignoring message from client {}: {}", self.out.connection_id(), msg);
+            return Ok(())
+        }
+        let expected = self.subscribe_message.clone().unwrap_or_default();
+        match msg {
+            ws::Message::Text(ref text) if same_subscription(&expected, text) => {
+                self.stream();
+                Ok(())
+            },
+            _ => {
+                warn!("unexpected subscription from client {}: {}", self.out.connection_id(), msg);
+                self.out.close_with_reason(ws::CloseCode::Policy, "the subscription does not match the capture")
+            },
+        }
+    }
+
+    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+        info!("client {} disconnected", self.out.connection_id());
+        self.stop.request();
+    }
+
+    fn on_shutdown(&mut self) {
+        self.stop.request();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subscription_test() {
+        let expected = r#"{"type":"subscribe","product_ids":["BTC-USD"]}"#;
+        assert!(same_subscription(expected, r#"{ "product_ids": ["BTC-USD"], "type": "subscribe" }"#));
+        assert!(!same_subscription(expected, r#"{"type":"subscribe","product_ids":["ETH-USD"]}"#));
+    }
+}
@@ -0,0 +1,70 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// A shutdown request shared by the threads of a process, the clones all refer
+/// to the same request.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    /// A request that has not been made yet.
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Requests the shutdown and wakes up the waiting threads.
+    pub fn request(&self) {
+        let (ref requested, ref condvar) = *self.state;
+        *requested.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    /// Whether the shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Sleeps for at most `timeout`, returns true if the shutdown has been requested.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (ref requested, ref condvar) = *self.state;
+        let guard = requested.lock().unwrap();
+        let (guard, _) = condvar.wait_timeout_while(guard, timeout, |requested| !*requested).unwrap();
+        *guard
+    }
+
+    /// Requests the shutdown on SIGINT or SIGTERM.
+    pub fn on_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("received signal {}, shutting down", signal);
+                shutdown.request();
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shutdown_test() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.wait(Duration::from_millis(1)));
+        let clone = shutdown.clone();
+        let waiter = thread::spawn(move || clone.wait(Duration::from_secs(60)));
+        shutdown.request();
+        assert!(waiter.join().unwrap());
+        assert!(shutdown.is_requested());
+    }
+}
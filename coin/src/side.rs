@@ -1,14 +1,29 @@
+use error::Error;
+
+/// The side of an order, or of a book.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Side {
+    /// The bids.
     Buy,
+    /// The asks.
     Sell,
 }
 
 impl Side {
-    pub fn of_str(str : &str) -> Result<Side, String> {
+    /// Parses buy or sell.
+    pub fn of_str(str : &str) -> Result<Side, Error> {
         match str {
             "buy" => Ok(Side::Buy),
             "sell" => Ok(Side::Sell),
-            _ => Err(format!("unknown side {}", str)),
+            _ => Err(Error::Parse(format!("unknown side {}", str))),
+        }
+    }
+
+    /// The side an order of this side trades against.
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
         }
     }
 }
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use rand;
+use rand::Rng;
+
+use error::Error;
+use shutdown::Shutdown;
+
+/// The delays to wait for between reconnection attempts. The delay is multiplied
+/// by `multiplier` after each connection that did not receive any message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    /// The delay after the first failed connection.
+    pub initial: Duration,
+    /// The longest delay.
+    pub max: Duration,
+    /// The growth of the delay after each failure.
+    pub multiplier: f64,
+    /// The delays are randomly spread by +/- this fraction, so that processes
+    /// that lost their connection at the same time do not reconnect in lockstep.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before reconnecting after `failures` consecutive failed connections.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let max = self.max.as_secs_f64();
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(failures as i32);
+        Duration::from_secs_f64(delay.min(max))
+    }
+
+    /// The delay spread by the jitter.
+    pub fn jittered_delay(&self, failures: u32) -> Duration {
+        let delay = self.delay(failures).as_secs_f64();
+        let jitter = self.jitter * rand::thread_rng().gen_range(-1.0..=1.0);
+        Duration::from_secs_f64((delay * (1.0 + jitter)).max(0.0))
+    }
+}
+
+/// Keeps a feed connected, reconnecting whenever the connection is closed
+/// until the shutdown is requested. Each connection is numbered by an epoch
+/// starting at 1 and `connect` returns the number of messages received once the
+/// connection has been closed.
+pub fn run<F>(backoff: &Backoff, shutdown: &Shutdown, mut connect: F)
+    where F: FnMut(u64) -> Result<u64, Error> {
+    let mut failures = 0;
+    let mut epoch = 0;
+    while !shutdown.is_requested() {
+        epoch += 1;
+        match connect(epoch) {
+            Ok(messages) => {
+                info!("connection epoch {} closed after {} messages", epoch, messages);
+                if messages == 0 {
+                    failures += 1;
+                } else {
+                    failures = 0;
+                }
+            },
+            Err(error) => {
+                error!("connection epoch {} failed: {}", epoch, error);
+                failures += 1;
+            },
+        }
+        if shutdown.is_requested() {
+            break
+        }
+        let delay = backoff.jittered_delay(failures);
+        info!("reconnecting in {:?}", delay);
+        if shutdown.wait(delay) {
+            break
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_test() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.1,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+        for failures in 0..10 {
+            let delay = backoff.jittered_delay(failures).as_secs_f64();
+            let expected = backoff.delay(failures).as_secs_f64();
+            assert!(delay >= 0.9 * expected - 1e-9 && delay <= 1.1 * expected + 1e-9);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+// A directory of its own for a test, the tests of a run share the process id.
+pub fn temp_directory(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("coin-{}-{}", name, process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
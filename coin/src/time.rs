@@ -1,36 +1,100 @@
+use std;
 use chrono;
-use chrono::TimeZone;
 
-const FORMAT: &'static str = "%Y-%m-%d %H:%M:%S.%f";
+use error::Error;
+
+const FORMAT: &str = "%Y-%m-%d %H:%M:%S.%f";
+/// The length of a formatted time.
 pub const LEN: usize = 29;
 
-#[derive(Clone)]
+/// A UTC time with nanosecond precision, formatted as 2018-01-17 21:00:00.000000000.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Time(chrono::DateTime<chrono::Utc>);
 
 impl Time {
-    pub fn to_string(&self) -> String {
-        let &Time(ref time) = self;
-        time.format(FORMAT).to_string()
-    }
-
+    /// The current system time.
     pub fn now() -> Time {
         Time(chrono::Utc::now())
     }
 
-    pub fn parse(str: &str) -> Result<Time, String> {
-        chrono::Utc.datetime_from_str(str, FORMAT)
-            .map(|e| Time(e))
-            .map_err(|e| e.to_string())
+    /// Parses a time as formatted by Display.
+    pub fn parse(str: &str) -> Result<Time, Error> {
+        chrono::NaiveDateTime::parse_from_str(str, FORMAT)
+            .map(|e| Time(e.and_utc()))
+            .map_err(|e| Error::Parse(format!("unable to parse as time {}: {}", str, e)))
+    }
+
+    /// Parses the timestamps used by exchanges in their json messages, e.g. 2014-11-07T08:19:27.028459Z.
+    pub fn parse_rfc3339(str: &str) -> Result<Time, Error> {
+        chrono::DateTime::parse_from_rfc3339(str)
+            .map(|e| Time(e.with_timezone(&chrono::Utc)))
+            .map_err(|e| Error::Parse(format!("unable to parse as time {}: {}", str, e)))
+    }
+
+    /// Builds a time from a number of milliseconds since the unix epoch.
+    pub fn of_millis(millis: i64) -> Result<Time, Error> {
+        chrono::DateTime::from_timestamp_millis(millis)
+            .map(Time)
+            .ok_or_else(|| Error::Parse(format!("timestamp out of range {}", millis)))
+    }
+
+    /// The number of nanoseconds since the unix epoch, as used in binary captures.
+    pub fn to_nanos(&self) -> i64 {
+        let Time(time) = self;
+        time.timestamp_nanos_opt().unwrap_or(i64::MAX)
+    }
+
+    /// Builds a time from a number of nanoseconds since the unix epoch.
+    pub fn of_nanos(nanos: i64) -> Time {
+        Time(chrono::DateTime::from_timestamp_nanos(nanos))
+    }
+
+    /// The time at a time of day such as 14:30 or 14:30:15.5 on the same day.
+    pub fn at_time_of_day(&self, str: &str) -> Result<Time, Error> {
+        let Time(time) = self;
+        chrono::NaiveTime::parse_from_str(str, "%H:%M:%S%.f")
+            .or_else(|_| chrono::NaiveTime::parse_from_str(str, "%H:%M"))
+            .map(|time_of_day| Time(time.date_naive().and_time(time_of_day).and_utc()))
+            .map_err(|e| Error::Parse(format!("unable to parse as time of day {}: {}", str, e)))
     }
 
+    /// The unix epoch, used as the time of what never happened.
     pub fn epoch() -> Time {
-        Time(chrono::DateTime::<chrono::Utc>::from_utc(
-            chrono::NaiveDateTime::from_timestamp(0, 0), chrono::Utc))
+        Time(chrono::DateTime::UNIX_EPOCH)
+    }
+
+    /// Formats the time with a chrono format string, e.g. %Y-%m-%d.
+    pub fn format(&self, format: &str) -> String {
+        let Time(time) = self;
+        time.format(format).to_string()
     }
 
+    /// The time after a duration, None if it is out of range.
+    pub fn checked_add(&self, duration: chrono::Duration) -> Option<Time> {
+        let Time(time) = self;
+        time.checked_add_signed(duration).map(Time)
+    }
+
+    /// The duration from `ref_time` to this time, negative if `ref_time` is later.
     pub fn signed_duration_since(&self, ref_time: &Time) -> chrono::Duration {
-        let &Time(ref time) = self;
-        let &Time(ref ref_time) = ref_time;
+        let Time(time) = self;
+        let Time(ref_time) = ref_time;
         time.signed_duration_since(*ref_time)
     }
 }
+
+impl std::ops::Add<chrono::Duration> for Time {
+    type Output = Time;
+
+    fn add(self, duration: chrono::Duration) -> Time {
+        let Time(time) = self;
+        Time(time + duration)
+    }
+}
+
+impl std::fmt::Display for Time {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let Time(time) = self;
+        time.format(FORMAT).fmt(f)
+    }
+}
@@ -0,0 +1,103 @@
+use error::Error;
+use side::Side;
+use price::Price;
+use quantity::Quantity;
+use time::Time;
+
+/// A trade as reported by an exchange, normalized across feeds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trade {
+    /// The product as named by the exchange.
+    pub product_id: String,
+    /// The identifier of the trade, increasing for each product.
+    pub trade_id: i64,
+    /// The price the trade happened at.
+    pub price: Price,
+    /// The traded size.
+    pub size: Quantity,
+    /// The side of the order that removed liquidity from the book, None for
+    /// trades that did not have one, e.g. auction fills.
+    pub aggressor: Option<Side>,
+    /// The time at which the trade happened according to the exchange.
+    pub exchange_time: Time,
+}
+
+/// Accumulates statistics on the trades of a single product.
+pub struct TradeProcessor {
+    last_trade: Option<Trade>,
+    trade_count: u64,
+    volume: Quantity,
+    buy_volume: Quantity,
+}
+
+impl Default for TradeProcessor {
+    fn default() -> TradeProcessor {
+        TradeProcessor::new()
+    }
+}
+
+impl TradeProcessor {
+    /// A processor that has not seen any trade.
+    pub fn new() -> TradeProcessor {
+        TradeProcessor {
+            last_trade: None,
+            trade_count: 0,
+            volume: Quantity::zero(),
+            buy_volume: Quantity::zero(),
+        }
+    }
+
+    /// The statistics are left untouched if the volumes would overflow.
+    pub fn on_trade(&mut self, trade: Trade) -> Result<(), Error> {
+        let mut volume = self.volume;
+        let mut buy_volume = self.buy_volume;
+        volume.accumulate(trade.size)?;
+        if trade.aggressor == Some(Side::Buy) {
+            buy_volume.accumulate(trade.size)?;
+        }
+        self.trade_count += 1;
+        self.volume = volume;
+        self.buy_volume = buy_volume;
+        self.last_trade = Some(trade);
+        Ok(())
+    }
+
+    /// Updates the last trade without counting it, e.g. for a trade that happened
+    /// before subscribing or that is reported again by a ticker. Trades that are not
+    /// more recent than the last one are ignored.
+    pub fn on_last_trade(&mut self, trade: Trade) {
+        match self.last_trade {
+            Some(ref last_trade) if trade.trade_id <= last_trade.trade_id => (),
+            _ => self.last_trade = Some(trade),
+        }
+    }
+
+    /// The most recent trade, counted or not.
+    pub fn last_trade(&self) -> Option<&Trade> {
+        self.last_trade.as_ref()
+    }
+
+    /// The number of trades counted.
+    pub fn trade_count(&self) -> u64 {
+        self.trade_count
+    }
+
+    /// The total traded size.
+    pub fn volume(&self) -> Quantity {
+        self.volume
+    }
+
+    /// The traded size for which the aggressor was a buyer.
+    pub fn buy_volume(&self) -> Quantity {
+        self.buy_volume
+    }
+
+    /// Logs the statistics and the last trade.
+    pub fn log_summary(&self) {
+        info!("trades {} volume {} (buy {}): last {:?}",
+            self.trade_count,
+            self.volume,
+            self.buy_volume,
+            self.last_trade.as_ref().map(|trade| (&trade.price, trade.size, trade.aggressor)));
+    }
+}
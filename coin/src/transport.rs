@@ -0,0 +1,374 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono;
+
+use capture::{CaptureReader, MergeReader, Record};
+use clock::{ReplayClock, Speed};
+use error::Error;
+use message_processor::{Action, MessageProcessor};
+use time::Time;
+
+/// Where a feed is served and how to subscribe to it once connected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    /// The websocket url, e.g. wss://ws-feed.gdax.com.
+    pub server_name: String,
+    /// The message to send once connected, if any.
+    pub subscribe_message: Option<String>,
+}
+
+/// Delivers the messages of a feed to a processor. The transport owns the
+/// connection concerns, the processor only decodes the messages.
+pub trait Transport {
+    /// Runs until the transport has no more messages to deliver.
+    fn run(&self, processor: &dyn MessageProcessor) -> Result<(), Error>;
+}
+
+/// Delivers a message to the processor, errors are logged as a bad message
+/// should not stop the feed. Sequence errors are only worth a warning as the
+/// processors recover from them by themselves.
+pub fn deliver(processor: &dyn MessageProcessor, now: &Time, message: &str) {
+    match processor.on_message(now, message) {
+        Ok(()) => (),
+        Err(ref error) if error.is_sequence_error() => warn!("{}", error),
+        Err(error) => error!("skipping message: {}", error.in_message(message)),
+    }
+}
+
+/// Replays a capture written by a Logger, in the text or binary format. The
+/// capture may be compressed, and if it has been rotated its parts are replayed
+/// in order. A capture written for another feed or in an unsupported format
+/// version is refused.
+pub struct ReplayFile {
+    filename: String,
+    from: Option<Time>,
+    to: Option<Time>,
+    clock: Arc<ReplayClock>,
+    tick: Option<Duration>,
+}
+
+impl ReplayFile {
+    /// Replays the whole capture at full speed.
+    pub fn new(filename: &str) -> ReplayFile {
+        ReplayFile {
+            filename: filename.to_string(),
+            from: None,
+            to: None,
+            clock: Arc::new(ReplayClock::new(Speed::Max)),
+            tick: None,
+        }
+    }
+
+    /// Paces the replay, the clock can be shared with the components that need
+    /// the current time. The replay stops when the shutdown of the clock is requested.
+    pub fn with_clock(self, clock: Arc<ReplayClock>) -> ReplayFile {
+        ReplayFile { clock, ..self }
+    }
+
+    /// Only replays the messages received between `from` and `to`, both included.
+    pub fn with_range(self, from: Option<Time>, to: Option<Time>) -> ReplayFile {
+        ReplayFile { from, to, ..self }
+    }
+
+    /// Ticks the processor every `tick` of recorded time during the gaps between
+    /// messages, as the live transports do while waiting for messages.
+    pub fn with_tick(self, tick: Duration) -> ReplayFile {
+        ReplayFile { tick: Some(tick), ..self }
+    }
+}
+
+// Advances the clock to the time of the next record, ticking the processors on
+// the way. Returns false if the shutdown has been requested meanwhile.
+fn advance(clock: &ReplayClock, tick: Option<Duration>, last: &mut Option<Time>, time: &Time, processors: &[&dyn MessageProcessor]) -> bool {
+    if let (Some(tick), Some(last)) = (tick, last.as_ref()) {
+        let tick = chrono::Duration::from_std(tick).unwrap_or_else(|_| chrono::Duration::zero());
+        let mut next = last.clone() + tick;
+        while tick > chrono::Duration::zero() && next < *time {
+            if !clock.advance_to(&next) {
+                return false
+            }
+            for processor in processors {
+                processor.on_tick(&next);
+            }
+            next = next + tick;
+        }
+    }
+    // The messages recorded out of order do not move the ticks back.
+    if last.as_ref().is_none_or(|last| *time > *last) {
+        *last = Some(time.clone());
+    }
+    clock.advance_to(time)
+}
+
+// Delivers a replayed record, the processor has to be the one of its feed.
+fn replay(filename: &str, processor: &dyn MessageProcessor, record: &Record, epoch: &mut Option<u64>) -> Result<(), Error> {
+    match record.feed {
+        Some(feed) if feed != processor.feed() => {
+            return Err(Error::Protocol(format!("{} is a {} capture, not {}", filename, feed, processor.feed())))
+        },
+        _ => (),
+    }
+    // The reconnections are replayed for the binary captures which keep them.
+    if *epoch != Some(record.epoch) {
+        processor.on_connect(record.epoch);
+        *epoch = Some(record.epoch);
+    }
+    deliver(processor, &record.time, &record.payload);
+    // There is no connection to act on when replaying.
+    for action in processor.pending_actions() {
+        info!("ignoring action {:?}", action);
+    }
+    Ok(())
+}
+
+impl Transport for ReplayFile {
+    fn run(&self, processor: &dyn MessageProcessor) -> Result<(), Error> {
+        let reader = CaptureReader::open(&self.filename)?.with_range(self.from.clone(), self.to.clone())?;
+        let mut epoch = None;
+        let mut last = None;
+        for record in reader {
+            let record = record?;
+            if !advance(&self.clock, self.tick, &mut last, &record.time, &[processor]) {
+                info!("replay of {} interrupted at {}", self.filename, record.time);
+                break
+            }
+            replay(&self.filename, processor, &record, &mut epoch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays several captures together in receive time order, each into its own
+/// processor, so that the processors publishing on the same bus see the messages
+/// interleaved as they were received.
+pub struct MergedReplay {
+    filenames: Vec<String>,
+    from: Option<Time>,
+    to: Option<Time>,
+    clock: Arc<ReplayClock>,
+    tick: Option<Duration>,
+}
+
+impl MergedReplay {
+    /// Replays the whole captures at full speed.
+    pub fn new(filenames: &[String]) -> MergedReplay {
+        MergedReplay {
+            filenames: filenames.to_vec(),
+            from: None,
+            to: None,
+            clock: Arc::new(ReplayClock::new(Speed::Max)),
+            tick: None,
+        }
+    }
+
+    /// Paces the replay, see ReplayFile::with_clock.
+    pub fn with_clock(self, clock: Arc<ReplayClock>) -> MergedReplay {
+        MergedReplay { clock, ..self }
+    }
+
+    /// Only replays the messages received between `from` and `to`, both included.
+    pub fn with_range(self, from: Option<Time>, to: Option<Time>) -> MergedReplay {
+        MergedReplay { from, to, ..self }
+    }
+
+    /// All the processors are ticked together.
+    pub fn with_tick(self, tick: Duration) -> MergedReplay {
+        MergedReplay { tick: Some(tick), ..self }
+    }
+
+    /// Takes a processor per capture, in the same order as the filenames.
+    pub fn run(&self, processors: &[&dyn MessageProcessor]) -> Result<(), Error> {
+        if processors.len() != self.filenames.len() {
+            return Err(Error::Parse(format!("{} captures but {} processors", self.filenames.len(), processors.len())))
+        }
+        let readers = self.filenames.iter()
+            .map(|filename| CaptureReader::open(filename)?.with_range(self.from.clone(), self.to.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut epochs = vec![None; processors.len()];
+        let mut last = None;
+        for record in MergeReader::new(readers) {
+            let (source, record) = record?;
+            if !advance(&self.clock, self.tick, &mut last, &record.time, processors) {
+                info!("merged replay interrupted at {}", record.time);
+                break
+            }
+            replay(&self.filenames[source], processors[source], &record, &mut epochs[source])?;
+        }
+        Ok(())
+    }
+}
+
+/// Delivers a fixed list of messages and records the actions requested by the
+/// processor, this is mostly useful to test processors.
+pub struct InMemory {
+    messages: Vec<(Time, String)>,
+    actions: RefCell<Vec<Action>>,
+}
+
+impl InMemory {
+    /// Delivers the messages with their receive time.
+    pub fn new(messages: Vec<(Time, String)>) -> InMemory {
+        InMemory { messages, actions: RefCell::new(Vec::new()) }
+    }
+
+    /// The actions requested during the last run.
+    pub fn actions(&self) -> Vec<Action> {
+        self.actions.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Transport for InMemory {
+    fn run(&self, processor: &dyn MessageProcessor) -> Result<(), Error> {
+        self.actions.borrow_mut().clear();
+        processor.on_connect(1);
+        for (now, message) in &self.messages {
+            deliver(processor, now, message);
+            self.actions.borrow_mut().extend(processor.pending_actions());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+    use book_processor::NotLiveStatus;
+    use capture::{CaptureWriter, Header, Rotation};
+    use clock::Clock;
+    use event::{Event, EventBus, Feed, FeedEvent};
+    use gdax;
+    use gemini::JsonProcessor;
+    use product::Registry;
+    use test_util::temp_directory;
+
+    #[test]
+    fn in_memory_test() {
+        let processor = JsonProcessor::new("btcusd", &Registry::default(), Rc::new(EventBus::new()));
+        let now = Time::now();
+        let initial = r#"{"type":"update","eventId":1,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"0.5","remaining":"0.5","side":"bid"}]}"#;
+        let gap = r#"{"type":"update","eventId":3,"socket_sequence":2,"events":[]}"#;
+        let transport = InMemory::new(vec![
+            (now.clone(), initial.to_string()),
+            (now.clone(), "not json".to_string()),
+            (now.clone(), gap.to_string()),
+        ]);
+        assert_eq!(transport.run(&processor), Ok(()));
+        assert_eq!(transport.actions(), vec![Action::Reconnect]);
+        assert!(processor.book().best_bid().is_some());
+    }
+
+    #[test]
+    fn sequence_error_test() {
+        let bus = Rc::new(EventBus::new());
+        let trades = Rc::new(RefCell::new(Vec::new()));
+        {
+            let trades = trades.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| {
+                if let Event::Trade(ref trade) = event.event {
+                    trades.borrow_mut().push(trade.trade_id);
+                }
+            }));
+        }
+        let processor = gdax::JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), bus);
+        let now = Time::now();
+        let match_ = |trade_id, sequence| format!(r#"{{"type": "match", "trade_id": {}, "sequence": {}, "time": "2014-11-07T08:19:29.028459Z", "product_id": "BTC-USD", "size": "1", "price": "10.5", "side": "sell"}}"#, trade_id, sequence);
+        deliver(&processor, &now, &match_(21, 91));
+        // The processor reports the gap once it has processed the message and
+        // requested the resync, the trade is still published.
+        deliver(&processor, &now, &match_(23, 95));
+        assert_eq!(*trades.borrow(), vec![21, 23]);
+        assert_eq!(processor.pending_actions().len(), 2);
+        deliver(&processor, &now, &match_(24, 96));
+        assert_eq!(*trades.borrow(), vec![21, 23, 24]);
+        assert!(processor.pending_actions().is_empty());
+    }
+
+    #[test]
+    fn merged_replay_test() {
+        let directory = temp_directory("merged-replay");
+        let write = |processor: &dyn MessageProcessor, name: &str, messages: &[(&str, &str)]| -> String {
+            let filename = directory.join(name).to_string_lossy().into_owned();
+            let mut writer = CaptureWriter::create(&filename, Rotation::Never).unwrap();
+            writer.set_header(Header::new(processor.feed(), processor.products(), &processor.endpoint()));
+            for &(time, payload) in messages {
+                let time = Time::parse(&format!("2018-01-17 21:00:{}", time)).unwrap();
+                writer.write(&Record { time, feed: Some(processor.feed()), epoch: 1, payload: payload.to_string() }).unwrap();
+            }
+            filename
+        };
+        let bus = Rc::new(EventBus::new());
+        let feeds = Rc::new(RefCell::new(Vec::new()));
+        {
+            let feeds = feeds.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| feeds.borrow_mut().push(event.feed)));
+        }
+        let gdax = gdax::JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), bus.clone());
+        let gemini = JsonProcessor::new("btcusd", &Registry::default(), bus.clone());
+        let filenames = vec![
+            write(&gdax, "gdax.log", &[
+                ("00.000000000", r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#),
+                ("02.000000000", r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["buy", "10.75", "2"]]}"#),
+            ]),
+            write(&gemini, "gemini.log.gz", &[
+                ("01.000000000", r#"{"type":"update","eventId":1,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"0.5","remaining":"0.5","side":"bid"}]}"#),
+            ]),
+        ];
+        assert_eq!(MergedReplay::new(&filenames).run(&[&gdax, &gemini]), Ok(()));
+        let mut order = feeds.borrow().clone();
+        order.dedup();
+        assert_eq!(order, vec![Feed::Gdax, Feed::Gemini, Feed::Gdax]);
+        // A capture is refused by the processor of another feed.
+        assert!(MergedReplay::new(&filenames).run(&[&gemini, &gdax]).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn paced_gap_test() {
+        let directory = temp_directory("paced-gap");
+        let filename = directory.join("gdax.log").to_string_lossy().into_owned();
+        let clock = Arc::new(ReplayClock::new(Speed::Scaled(10.0)));
+        let bus = Rc::new(EventBus::new());
+        let statuses = Rc::new(RefCell::new(Vec::new()));
+        {
+            let statuses = statuses.clone();
+            bus.subscribe(Box::new(move |event: &FeedEvent| {
+                match event.event {
+                    Event::StatusChange { ref status, .. } => statuses.borrow_mut().push((event.time.clone(), Some(status.clone()))),
+                    Event::Heartbeat { .. } => statuses.borrow_mut().push((event.time.clone(), None)),
+                    _ => (),
+                }
+            }));
+        }
+        let processor = gdax::JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), bus)
+            .with_clock(clock.clone());
+        let mut writer = CaptureWriter::create(&filename, Rotation::Never).unwrap();
+        writer.set_header(Header::new(processor.feed(), processor.products(), &processor.endpoint()));
+        for &(time, payload) in &[
+            ("00.000000000", r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#),
+            ("00.100000000", r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["buy", "10.75", "2"]]}"#),
+            ("01.000000000", r#"{"type": "heartbeat", "product_id": "BTC-USD", "sequence": 90, "last_trade_id": 20, "time": "2018-01-17T21:00:01.000000Z"}"#),
+            ("02.000000000", r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["sell", "10.9", "1"]]}"#),
+        ] {
+            let time = Time::parse(&format!("2018-01-17 21:00:{}", time)).unwrap();
+            writer.write(&Record { time, feed: Some(Feed::Gdax), epoch: 1, payload: payload.to_string() }).unwrap();
+        }
+        drop(writer);
+        let replay = ReplayFile::new(&filename).with_clock(clock.clone()).with_tick(Duration::from_millis(100));
+        assert_eq!(replay.run(&processor), Ok(()));
+        // The book goes stale during the gap, before the update makes it live again.
+        // The heartbeat does not count as an update of the book.
+        let statuses = statuses.borrow();
+        assert_eq!(statuses.iter().map(|(_, status)| status.clone()).collect::<Vec<_>>(),
+            vec![Some(Ok(())), Some(Err(NotLiveStatus::Stale)), None, Some(Ok(()))]);
+        let stale_time = &statuses[1].0;
+        assert!(*stale_time >= Time::parse("2018-01-17 21:00:00.600000000").unwrap());
+        assert!(*stale_time < Time::parse("2018-01-17 21:00:02.000000000").unwrap());
+        assert!(clock.now() >= Time::parse("2018-01-17 21:00:02.000000000").unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
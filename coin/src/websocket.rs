@@ -0,0 +1,175 @@
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ws;
+use ws::util::Token;
+
+use clock::{Clock, SystemClock};
+use error::Error;
+use message_processor::{Action, MessageProcessor};
+use shutdown::Shutdown;
+use supervisor;
+use supervisor::Backoff;
+use transport::{deliver, Endpoint, Transport};
+
+const PING: Token = Token(1);
+const SHUTDOWN: Token = Token(2);
+
+// How often the connection checks for a shutdown request.
+const SHUTDOWN_CHECK_MS: u64 = 100;
+
+/// Keeps a processor connected to a WebSocket server: sends the subscription
+/// message, checks that the server is alive with pings and reconnects whenever
+/// the connection is lost.
+pub struct WebSocket {
+    endpoint: Endpoint,
+    backoff: Backoff,
+    ping_interval: Duration,
+    // The connection is dropped when nothing has been received for this long.
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    shutdown: Shutdown,
+}
+
+impl WebSocket {
+    /// Connects to the endpoint with the default backoff and pings.
+    pub fn new(endpoint: Endpoint) -> WebSocket {
+        WebSocket {
+            endpoint,
+            backoff: Backoff::default(),
+            ping_interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            clock: Arc::new(SystemClock),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// The delays between the reconnections.
+    pub fn with_backoff(self, backoff: Backoff) -> WebSocket {
+        WebSocket { backoff, ..self }
+    }
+
+    /// How often to ping the server and how long to wait for any frame before reconnecting.
+    pub fn with_ping(self, ping_interval: Duration, timeout: Duration) -> WebSocket {
+        WebSocket { ping_interval, timeout, ..self }
+    }
+
+    /// The clock giving the receive time of the messages.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> WebSocket {
+        WebSocket { clock, ..self }
+    }
+
+    /// The transport stops once the shutdown is requested.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> WebSocket {
+        WebSocket { shutdown, ..self }
+    }
+
+    /// The server connected to.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    // Runs a single connection, returns the number of messages received once
+    // it has been closed.
+    #[allow(clippy::result_large_err)]
+    fn connect(&self, processor: &dyn MessageProcessor) -> ws::Result<u64> {
+        let messages = Cell::new(0);
+        ws::connect(self.endpoint.server_name.clone(), |out| {
+            Connection {
+                out,
+                processor,
+                transport: self,
+                messages: &messages,
+                last_received: Instant::now(),
+                closing: false,
+            }
+        })?;
+        Ok(messages.get())
+    }
+}
+
+impl Transport for WebSocket {
+    fn run(&self, processor: &dyn MessageProcessor) -> Result<(), Error> {
+        supervisor::run(&self.backoff, &self.shutdown, |epoch| {
+            info!("connection epoch {} to {}", epoch, self.endpoint.server_name);
+            processor.on_connect(epoch);
+            self.connect(processor).map_err(Error::from)
+        });
+        Ok(())
+    }
+}
+
+struct Connection<'a> {
+    out: ws::Sender,
+    processor: &'a dyn MessageProcessor,
+    transport: &'a WebSocket,
+    messages: &'a Cell<u64>,
+    last_received: Instant,
+    // Set once the close handshake has been started on shutdown.
+    closing: bool,
+}
+
+impl<'a> ws::Handler for Connection<'a> {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        if let Some(ref message) = self.transport.endpoint.subscribe_message {
+            self.out.send(&message[..])?;
+            info!("succesfully sent subscription message");
+        }
+        self.out.timeout(SHUTDOWN_CHECK_MS, SHUTDOWN)?;
+        self.out.timeout(self.transport.ping_interval.as_millis() as u64, PING)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        self.messages.set(self.messages.get() + 1);
+        match msg {
+            ws::Message::Binary(vec) => {
+                error!("unexpected binary message {:?}", vec);
+            }
+            ws::Message::Text(msg) => {
+                deliver(self.processor, &self.transport.clock.now(), &msg);
+                for action in self.processor.pending_actions() {
+                    match action {
+                        Action::Send(message) => self.out.send(message)?,
+                        Action::Reconnect => self.out.close(ws::CloseCode::Normal)?,
+                    }
+                }
+            }
+        };
+        Ok(())
+    }
+
+    // Any frame, including pongs, shows that the server is alive.
+    fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        self.last_received = Instant::now();
+        Ok(Some(frame))
+    }
+
+    fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
+        if event == SHUTDOWN {
+            if !self.transport.shutdown.is_requested() {
+                return self.out.timeout(SHUTDOWN_CHECK_MS, SHUTDOWN)
+            }
+            if self.closing {
+                // The server did not complete the close handshake in time.
+                return self.out.shutdown()
+            }
+            info!("closing the connection on shutdown");
+            self.closing = true;
+            self.out.close(ws::CloseCode::Away)?;
+            return self.out.timeout(1000, SHUTDOWN)
+        }
+        if event != PING {
+            return Ok(());
+        }
+        let silence = self.last_received.elapsed();
+        if silence > self.transport.timeout {
+            // The server is unlikely to answer a close handshake.
+            warn!("nothing received for {:?}, dropping the connection", silence);
+            return self.out.shutdown();
+        }
+        self.out.ping(Vec::new())?;
+        self.processor.on_tick(&self.transport.clock.now());
+        self.out.timeout(self.transport.ping_interval.as_millis() as u64, PING)
+    }
+}
@@ -0,0 +1,96 @@
+// Runs the live path, a WebSocket transport and a processor, against captures
+// streamed by a local replay server.
+extern crate coin;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use coin::capture::{CaptureWriter, Header, Record, Rotation};
+use coin::clock::Speed;
+use coin::event::{Event, EventBus, Feed, FeedEvent};
+use coin::gdax;
+use coin::gemini;
+use coin::message_processor::MessageProcessor;
+use coin::product::Registry;
+use coin::server::ReplayServer;
+use coin::shutdown::Shutdown;
+use coin::time::Time;
+use coin::transport::{Endpoint, Transport};
+use coin::websocket::WebSocket;
+
+fn capture(name: &str, processor: &dyn MessageProcessor, messages: &[&str]) -> (PathBuf, String) {
+    let directory = env::temp_dir().join(format!("coin-{}-{}", name, process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let filename = directory.join("capture.log.gz").to_string_lossy().into_owned();
+    let mut writer = CaptureWriter::create(&filename, Rotation::Never).unwrap();
+    writer.set_header(Header::new(processor.feed(), processor.products(), &processor.endpoint()));
+    for message in messages {
+        let record = Record { time: Time::now(), feed: Some(processor.feed()), epoch: 1, payload: message.to_string() };
+        writer.write(&record).unwrap();
+    }
+    (directory, filename)
+}
+
+// Streams the capture to the processor until `done` holds for an event.
+fn run<F: Fn(&FeedEvent) -> bool + 'static>(filename: &str, processor: &dyn MessageProcessor, bus: &EventBus, done: F) {
+    let server_shutdown = Shutdown::new();
+    let server = ReplayServer::bind("127.0.0.1:0", filename, Speed::Max).unwrap()
+        .with_shutdown(server_shutdown.clone());
+    let address = server.local_addr().unwrap();
+    let server = thread::spawn(move || server.run());
+
+    let shutdown = Shutdown::new();
+    {
+        let shutdown = shutdown.clone();
+        bus.subscribe(Box::new(move |event: &FeedEvent| if done(event) { shutdown.request() }));
+    }
+    // Fails the test rather than hanging it.
+    let timeout = shutdown.clone();
+    thread::spawn(move || if !timeout.wait(Duration::from_secs(10)) { timeout.request() });
+
+    let endpoint = Endpoint { server_name: format!("ws://{}", address), ..processor.endpoint() };
+    WebSocket::new(endpoint).with_shutdown(shutdown).run(processor).unwrap();
+    server_shutdown.request();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn gemini_test() {
+    let bus = Rc::new(EventBus::new());
+    let processor = gemini::JsonProcessor::new("btcusd", &Registry::default(), bus.clone());
+    let (directory, filename) = capture("gemini-server", &processor, &[
+        r#"{"type":"update","eventId":1,"socket_sequence":0,"events":[{"type":"change","reason":"initial","price":"3641.61","delta":"0.5","remaining":"0.5","side":"bid"}]}"#,
+        r#"{"type":"update","eventId":2,"socket_sequence":1,"events":[{"type":"change","reason":"place","price":"3642.5","delta":"1","remaining":"1","side":"ask"}]}"#,
+    ]);
+    run(&filename, &processor, &bus, |event| match event.event {
+        Event::LevelUpdate { ref price, .. } => price.to_string() == "3642.5",
+        _ => false,
+    });
+    assert_eq!(processor.book().best_bid().map(|(price, _)| price.to_string()), Some("3641.61".to_string()));
+    assert_eq!(processor.book().best_ask().map(|(price, _)| price.to_string()), Some("3642.5".to_string()));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+// The gdax messages are only streamed once the client has subscribed to the
+// products of the capture.
+#[test]
+fn gdax_test() {
+    let bus = Rc::new(EventBus::new());
+    let processor = gdax::JsonProcessor::new(vec!["BTC-USD".to_string()], &Registry::default(), bus.clone());
+    let (directory, filename) = capture("gdax-server", &processor, &[
+        r#"{"type": "snapshot", "product_id": "BTC-USD", "bids": [["10.5", "1.2"]], "asks": [["11", "3"]]}"#,
+        r#"{"type": "l2update", "product_id": "BTC-USD", "changes": [["buy", "10.75", "2"]]}"#,
+    ]);
+    run(&filename, &processor, &bus, |event| event.feed == Feed::Gdax && match event.event {
+        Event::LevelUpdate { ref price, .. } => price.to_string() == "10.75",
+        _ => false,
+    });
+    let book = processor.book("BTC-USD").unwrap();
+    assert_eq!(book.best_bid().map(|(price, _)| price.to_string()), Some("10.75".to_string()));
+    fs::remove_dir_all(&directory).unwrap();
+}